@@ -0,0 +1,216 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the audio folder looked for next to each TextGrid folder.
+const SIBLING_WAV_FOLDER: &str = "wav";
+
+/// Why the TextGrid files of a folder could not be listed.
+#[derive(Debug, thiserror::Error)]
+pub enum ScanError {
+    #[error("Invalid glob {pattern}: {source}")]
+    InvalidGlob { pattern: String, source: globset::Error },
+    #[error("Failed to read {}: {source}", path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+}
+
+/// Which TextGrid files under a folder are taken. Globs are matched against the path
+/// relative to the folder with `/` separators, where `*` stays within one folder and
+/// `**` spans any number of them. Without include globs every TextGrid is taken.
+#[derive(Clone, Debug, Default)]
+pub struct TextGridFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    recursive: bool,
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<Option<GlobSet>, ScanError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|source| ScanError::InvalidGlob { pattern: pattern.clone(), source })?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|source| ScanError::InvalidGlob { pattern: patterns.join(" "), source })
+}
+
+impl TextGridFilter {
+    pub fn new(include: &[String], exclude: &[String], recursive: bool) -> Result<Self, ScanError> {
+        Ok(TextGridFilter { include: build_glob_set(include)?, exclude: build_glob_set(exclude)?, recursive })
+    }
+
+    pub fn matches(&self, rel_path: &Path) -> bool {
+        self.include.as_ref().map_or(true, |g| g.is_match(rel_path)) && !self.exclude.as_ref().is_some_and(|g| g.is_match(rel_path))
+    }
+}
+
+fn is_textgrid(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()) == Some("textgrid".into())
+}
+
+/// The TextGrid files under `folder` that `filter` takes. Subfolders are only entered
+/// when the filter is recursive, symbolic links to folders are not followed and
+/// subfolders that cannot be read are left out.
+pub fn find_textgrids(folder: &Path, filter: &TextGridFilter) -> Result<Vec<PathBuf>, ScanError> {
+    let mut paths = Vec::new();
+    let mut pending = vec![folder.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(source) if dir == folder => return Err(ScanError::Io { path: dir, source }),
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                if filter.recursive {
+                    pending.push(path);
+                }
+            } else if is_textgrid(&path) && filter.matches(path.strip_prefix(folder).unwrap_or(&path)) {
+                paths.push(path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Finds the audio of a TextGrid, looking in order at the same relative folder under
+/// `wav_folder`, `wav_folder` itself, and a `wav` folder next to the TextGrid's own
+/// folder (`song/TextGrid/a.TextGrid` has `song/wav/a.wav`). When none of them has the
+/// file, it is expected directly in `wav_folder`.
+pub fn resolve_wav(tg_file: &Path, rel_path: &Path, wav_folder: Option<&Path>) -> Option<PathBuf> {
+    let wav_name = PathBuf::from(tg_file.file_name()?).with_extension("wav");
+    let mut candidates = Vec::new();
+    if let Some(wav_folder) = wav_folder {
+        candidates.push(wav_folder.join(rel_path).with_extension("wav"));
+        candidates.push(wav_folder.join(&wav_name));
+    }
+    if let Some(grandparent) = tg_file.parent().and_then(|p| p.parent()) {
+        candidates.push(grandparent.join(SIBLING_WAV_FOLDER).join(&wav_name));
+    }
+    candidates
+        .iter()
+        .find(|c| c.is_file())
+        .cloned()
+        .or_else(|| wav_folder.map(|w| w.join(&wav_name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(root: &Path, rel_path: &str) -> PathBuf {
+        let path = root.join(rel_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "").unwrap();
+        path
+    }
+
+    fn filter(include: &[&str], exclude: &[&str], recursive: bool) -> TextGridFilter {
+        let strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        TextGridFilter::new(&strings(include), &strings(exclude), recursive).unwrap()
+    }
+
+    fn found(root: &Path, filter: &TextGridFilter) -> Vec<String> {
+        let mut paths: Vec<String> = find_textgrids(root, filter)
+            .unwrap()
+            .iter()
+            .map(|p| p.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn single_star_stays_within_a_folder() {
+        let f = filter(&["*.TextGrid"], &[], true);
+        assert!(f.matches(Path::new("a.TextGrid")));
+        assert!(!f.matches(Path::new("song/a.TextGrid")));
+        let f = filter(&["**/*.TextGrid"], &[], true);
+        assert!(f.matches(Path::new("a.TextGrid")));
+        assert!(f.matches(Path::new("song/take/a.TextGrid")));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let f = filter(&["**/*.TextGrid"], &["old/**"], true);
+        assert!(f.matches(Path::new("new/a.TextGrid")));
+        assert!(!f.matches(Path::new("old/a.TextGrid")));
+        assert!(!f.matches(Path::new("old/take/a.TextGrid")));
+        assert!(filter(&[], &[], true).matches(Path::new("any/a.TextGrid")));
+    }
+
+    #[test]
+    fn invalid_glob() {
+        let result = TextGridFilter::new(&["a[".to_string()], &[], false);
+        assert!(matches!(result, Err(ScanError::InvalidGlob { .. })));
+    }
+
+    #[test]
+    fn finds_textgrids_flat_or_recursive() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "a.TextGrid");
+        touch(dir.path(), "b.textgrid");
+        touch(dir.path(), "notes.txt");
+        touch(dir.path(), "song/c.TextGrid");
+        touch(dir.path(), "song/take/d.TextGrid");
+        assert_eq!(found(dir.path(), &filter(&[], &[], false)), ["a.TextGrid", "b.textgrid"]);
+        assert_eq!(
+            found(dir.path(), &filter(&[], &[], true)),
+            ["a.TextGrid", "b.textgrid", "song/c.TextGrid", "song/take/d.TextGrid"]
+        );
+        assert_eq!(found(dir.path(), &filter(&["song/*"], &[], true)), ["song/c.TextGrid"]);
+    }
+
+    #[test]
+    fn unreadable_folder_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        assert!(matches!(find_textgrids(&missing, &TextGridFilter::default()), Err(ScanError::Io { path, .. }) if path == missing));
+    }
+
+    #[test]
+    fn wav_in_the_same_relative_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let tg_file = touch(dir.path(), "TextGrid/song/a.TextGrid");
+        let wav_folder = dir.path().join("audio");
+        let wav = touch(&wav_folder, "song/a.wav");
+        touch(&wav_folder, "a.wav");
+        assert_eq!(resolve_wav(&tg_file, Path::new("song/a.TextGrid"), Some(&wav_folder)), Some(wav));
+    }
+
+    #[test]
+    fn wav_directly_in_the_wav_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let tg_file = touch(dir.path(), "TextGrid/song/a.TextGrid");
+        let wav_folder = dir.path().join("audio");
+        let wav = touch(&wav_folder, "a.wav");
+        assert_eq!(resolve_wav(&tg_file, Path::new("song/a.TextGrid"), Some(&wav_folder)), Some(wav));
+    }
+
+    #[test]
+    fn wav_in_the_sibling_wav_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let tg_file = touch(dir.path(), "song/TextGrid/a.TextGrid");
+        let wav = touch(dir.path(), "song/wav/a.wav");
+        assert_eq!(resolve_wav(&tg_file, Path::new("a.TextGrid"), Some(&dir.path().join("audio"))), Some(wav.clone()));
+        assert_eq!(resolve_wav(&tg_file, Path::new("a.TextGrid"), None), Some(wav));
+    }
+
+    #[test]
+    fn missing_wav_is_expected_in_the_wav_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let tg_file = touch(dir.path(), "song/TextGrid/a.TextGrid");
+        let wav_folder = dir.path().join("audio");
+        assert_eq!(resolve_wav(&tg_file, Path::new("a.TextGrid"), Some(&wav_folder)), Some(wav_folder.join("a.wav")));
+        assert_eq!(resolve_wav(&tg_file, Path::new("a.TextGrid"), None), None);
+    }
+}
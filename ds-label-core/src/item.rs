@@ -0,0 +1,399 @@
+use crate::files::resolve_wav;
+use crate::project::ProjectState;
+use crate::replace::{apply_marks, MarkChoice, ReplaceError};
+use crate::rule::{find_marks, CompiledRule, ReplaceRule};
+use crate::textgrid::{write_textgrid, SourceEncoding, TextGrid, TextGridInterval};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A TextGrid with the marks the rules of a session found in it and the options
+/// chosen for them.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ItemRecord {
+    pub tg_file: PathBuf,
+    pub tg_stem: String,
+    /// Path relative to the TextGrid folder.
+    pub tg_rel_path: PathBuf,
+    pub tg_content: TextGrid,
+    pub tg_encoding: SourceEncoding,
+    pub word_tier_idx: Option<usize>,
+    pub phone_tier_idx: usize,
+    pub wav_file: Option<PathBuf>,
+    pub found_mark_idxs: Vec<Vec<usize>>,
+    pub found_mark_titles: Vec<String>,
+    pub found_mark_rules: Vec<String>,
+    pub found_mark_tiers: Vec<usize>,
+    pub replace_options: Vec<Vec<String>>,
+    pub selected_options: Vec<Option<i32>>,
+    pub original_options: Vec<Option<i32>>,
+    /// Marks the reviewer chose an option for or kept as they are.
+    pub decided: Vec<bool>,
+    pub dirty: bool,
+}
+
+/// A review decision kept in the project file, so it can be restored after a rescan.
+/// The mark is identified by its file relative to the TextGrid folder, its rule and the
+/// index of its first interval, and only restored while its intervals are unchanged.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MarkDecision {
+    pub file: PathBuf,
+    pub rule: String,
+    pub first_idx: usize,
+    pub content_hash: u64,
+    /// The chosen option, or none when the mark was kept as it is.
+    pub option: Option<String>,
+}
+
+/// The file, rule and first interval a decision was made on.
+pub type DecisionKey = (PathBuf, String, usize);
+
+/// Review decisions by the mark they were made on.
+pub type Decisions = HashMap<DecisionKey, MarkDecision>;
+
+impl MarkDecision {
+    pub fn key(&self) -> DecisionKey {
+        (self.file.clone(), self.rule.clone(), self.first_idx)
+    }
+}
+
+/// Why a scan of its file could not restore a saved decision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LostReason {
+    /// The rule no longer has the chosen option.
+    OptionRemoved,
+    /// The marked intervals changed since the decision was made.
+    MarkChanged,
+    /// The rule no longer matches at the first interval of the mark.
+    MarkGone,
+}
+
+/// FNV-1a over the labels and times of the marked intervals, stable across builds
+/// unlike the std hasher.
+pub fn mark_content_hash(intervals: &[TextGridInterval], mark_idxs: &[usize]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &i in mark_idxs {
+        let interval = &intervals[i];
+        let bytes = interval
+            .xmin
+            .value()
+            .to_bits()
+            .to_le_bytes()
+            .into_iter()
+            .chain(interval.xmax.value().to_bits().to_le_bytes())
+            .chain(interval.text.bytes())
+            .chain([0]);
+        for b in bytes {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Finds the marks of all `compiled_rules` in a TextGrid, returning its record when any
+/// rule matches. Rules earlier in the project win over later ones on the intervals they share.
+pub fn scan_textgrid(
+    project: &ProjectState,
+    compiled_rules: &[CompiledRule],
+    tg_folder: &Path,
+    path: &Path,
+    tg: TextGrid,
+    tg_encoding: SourceEncoding,
+) -> Option<ItemRecord> {
+    let (word_tier_idx, phone_tier_idx) = project.resolve_tiers(&tg)?;
+    // Marks as (rule, tier, interval indices, title).
+    let mut marks: Vec<(&ReplaceRule, usize, Vec<usize>, String)> = Vec::new();
+    for compiled_rule in compiled_rules.iter() {
+        let Some(target_tier_idx) = project.resolve_target_tier(compiled_rule.rule, &tg) else {
+            continue;
+        };
+        let (found_mark_idxs, found_mark_titles) = find_marks(compiled_rule, &tg, word_tier_idx, target_tier_idx);
+        let taken_before = marks.len();
+        for (mark_idxs, title) in found_mark_idxs.into_iter().zip(found_mark_titles) {
+            if marks[..taken_before].iter().any(|m| m.1 == target_tier_idx && m.2.iter().any(|i| mark_idxs.contains(i))) {
+                continue;
+            }
+            marks.push((compiled_rule.rule, target_tier_idx, mark_idxs, title));
+        }
+    }
+    if marks.is_empty() {
+        return None;
+    }
+    marks.sort_by(|a, b| tg.items[a.1].intervals[a.2[0]].xmin.value().total_cmp(&tg.items[b.1].intervals[b.2[0]].xmin.value()));
+    let tg_rel_path = path.strip_prefix(tg_folder).unwrap_or(path).to_path_buf();
+    Some(ItemRecord {
+        tg_file: path.to_path_buf(),
+        tg_stem: path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+        wav_file: resolve_wav(path, &tg_rel_path, project.wav_folder.as_deref()),
+        tg_rel_path,
+        tg_encoding,
+        word_tier_idx,
+        phone_tier_idx,
+        selected_options: vec![None; marks.len()],
+        original_options: vec![None; marks.len()],
+        decided: vec![false; marks.len()],
+        found_mark_rules: marks.iter().map(|m| m.0.rule_name.clone()).collect(),
+        found_mark_tiers: marks.iter().map(|m| m.1).collect(),
+        replace_options: marks.iter().map(|m| m.0.replace_options.clone()).collect(),
+        found_mark_titles: marks.iter().map(|m| m.3.clone()).collect(),
+        found_mark_idxs: marks.into_iter().map(|m| m.2).collect(),
+        tg_content: tg,
+        dirty: false,
+    })
+}
+
+/// The decisions made on the marks of an item that its file does not show yet, which
+/// are options not saved and marks kept as they are.
+pub fn item_decisions(item: &ItemRecord) -> impl Iterator<Item = MarkDecision> + '_ {
+    item.found_mark_idxs.iter().enumerate().filter_map(|(i, mark_idxs)| {
+        let selected = item.selected_options[i];
+        if !item.decided[i] || (selected.is_some() && selected == item.original_options[i]) {
+            return None;
+        }
+        Some(MarkDecision {
+            file: item.tg_rel_path.clone(),
+            rule: item.found_mark_rules[i].clone(),
+            first_idx: mark_idxs[0],
+            content_hash: mark_content_hash(&item.tg_content.items[item.found_mark_tiers[i]].intervals, mark_idxs),
+            option: selected.map(|o| item.replace_options[i][o as usize].clone()),
+        })
+    })
+}
+
+/// The unsaved decisions of `items` merged with `pending` ones. A decision made on an
+/// item replaces a pending one for the same mark.
+pub fn merge_decisions(items: &[ItemRecord], pending: &[MarkDecision]) -> Decisions {
+    let mut decisions: Decisions = items.iter().flat_map(item_decisions).map(|d| (d.key(), d)).collect();
+    for decision in pending {
+        decisions.entry(decision.key()).or_insert_with(|| decision.clone());
+    }
+    decisions
+}
+
+/// Selects the options of the marks `decisions` were made on, taking those decisions
+/// out. Returns the ones taken out that could not be restored.
+pub fn restore_decisions(item: &mut ItemRecord, decisions: &mut Decisions) -> Vec<(MarkDecision, LostReason)> {
+    let mut lost = Vec::new();
+    for (i, mark_idxs) in item.found_mark_idxs.iter().enumerate() {
+        let key = (item.tg_rel_path.clone(), item.found_mark_rules[i].clone(), mark_idxs[0]);
+        let Some(decision) = decisions.remove(&key) else {
+            continue;
+        };
+        if decision.content_hash != mark_content_hash(&item.tg_content.items[item.found_mark_tiers[i]].intervals, mark_idxs) {
+            lost.push((decision, LostReason::MarkChanged));
+            continue;
+        }
+        let Some(option) = &decision.option else {
+            item.selected_options[i] = None;
+            item.decided[i] = true;
+            continue;
+        };
+        match item.replace_options[i].iter().position(|o| o == option) {
+            Some(opt_idx) => {
+                item.selected_options[i] = Some(opt_idx as i32);
+                item.decided[i] = true;
+            }
+            None => lost.push((decision, LostReason::OptionRemoved)),
+        }
+    }
+    item.dirty = item.selected_options != item.original_options;
+    lost
+}
+
+/// Takes out the decisions left on `scanned_files` under `scanned_rules` once their
+/// items are restored, as their marks are gone.
+pub fn drop_stale_decisions(
+    decisions: &mut Decisions,
+    scanned_files: &HashSet<PathBuf>,
+    scanned_rules: &HashSet<&str>,
+) -> Vec<(MarkDecision, LostReason)> {
+    let mut lost = Vec::new();
+    decisions.retain(|_, d| {
+        let stale = scanned_files.contains(&d.file) && scanned_rules.contains(d.rule.as_str());
+        if stale {
+            lost.push((d.clone(), LostReason::MarkGone));
+        }
+        !stale
+    });
+    lost
+}
+
+/// Builds the TextGrid of an item with its selected options applied.
+pub fn apply_selected_options(item: &ItemRecord) -> Result<TextGrid, ReplaceError> {
+    let choices: Vec<MarkChoice> = (0..item.found_mark_idxs.len())
+        .filter_map(|i| {
+            item.selected_options[i].map(|opt_idx| MarkChoice {
+                tier_idx: item.found_mark_tiers[i],
+                mark_idxs: &item.found_mark_idxs[i],
+                option: &item.replace_options[i][opt_idx as usize],
+            })
+        })
+        .collect();
+    apply_marks(&item.tg_content, item.word_tier_idx, item.phone_tier_idx, &choices)
+}
+
+/// Writes an item with its selected options applied back to its TextGrid file.
+pub fn write_item(item: &ItemRecord, auto_backup: bool, force_utf8: bool) -> Result<(), crate::Error> {
+    let new_tg = apply_selected_options(item)?;
+    if auto_backup {
+        let bak_path = item.tg_file.clone().with_extension("TextGrid.bak");
+        if !bak_path.exists() {
+            fs::copy(&item.tg_file, &bak_path)?;
+        }
+    }
+    let encoding = if force_utf8 { SourceEncoding::default() } else { item.tg_encoding.clone() };
+    write_textgrid(&item.tg_file, &new_tg, &encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::textgrid::TextGridTier;
+
+    /// An item of `file` with one phone tier `a b c` and a mark of rule `r` on each
+    /// interval, whose options are `x` and `y`.
+    fn item(file: &str) -> ItemRecord {
+        let mut tg = TextGrid::default();
+        tg.items.push(TextGridTier {
+            intervals: ["a", "b", "c"]
+                .iter()
+                .enumerate()
+                .map(|(i, text)| TextGridInterval { xmin: (i as f64).into(), xmax: (i as f64 + 1.0).into(), text: text.to_string() })
+                .collect(),
+            ..Default::default()
+        });
+        ItemRecord {
+            tg_file: PathBuf::from("/data").join(file),
+            tg_stem: file.trim_end_matches(".TextGrid").to_string(),
+            tg_rel_path: PathBuf::from(file),
+            tg_content: tg,
+            tg_encoding: SourceEncoding::default(),
+            word_tier_idx: None,
+            phone_tier_idx: 0,
+            wav_file: None,
+            found_mark_idxs: vec![vec![0], vec![1], vec![2]],
+            found_mark_titles: vec!["a".into(), "b".into(), "c".into()],
+            found_mark_rules: vec!["r".into(); 3],
+            found_mark_tiers: vec![0; 3],
+            replace_options: vec![vec!["x".into(), "y".into()]; 3],
+            selected_options: vec![None; 3],
+            original_options: vec![None; 3],
+            decided: vec![false; 3],
+            dirty: false,
+        }
+    }
+
+    /// Decides a mark the way the review does, `None` keeping it as it is.
+    fn choose(item: &mut ItemRecord, mark_idx: usize, option: Option<i32>) {
+        item.selected_options[mark_idx] = option;
+        item.decided[mark_idx] = true;
+    }
+
+    fn decisions_of(item: &ItemRecord) -> Decisions {
+        merge_decisions(std::slice::from_ref(item), &[])
+    }
+
+    #[test]
+    fn content_hash_follows_labels_and_times() {
+        let a = item("a.TextGrid");
+        let hash = |item: &ItemRecord| mark_content_hash(&item.tg_content.items[0].intervals, &[1]);
+        let mut relabelled = a.clone();
+        relabelled.tg_content.items[0].intervals[1].text = "d".into();
+        let mut retimed = a.clone();
+        retimed.tg_content.items[0].intervals[1].xmax = 1.5.into();
+        assert_eq!(hash(&a), hash(&a.clone()));
+        assert_ne!(hash(&a), hash(&relabelled));
+        assert_ne!(hash(&a), hash(&retimed));
+    }
+
+    #[test]
+    fn decisions_are_unsaved_options_and_kept_marks() {
+        let mut a = item("a.TextGrid");
+        choose(&mut a, 0, Some(1));
+        choose(&mut a, 2, Some(0));
+        a.original_options[2] = Some(0);
+        let decisions: Vec<MarkDecision> = item_decisions(&a).collect();
+        assert_eq!(decisions.len(), 1);
+        assert_eq!((decisions[0].first_idx, decisions[0].option.as_deref()), (0, Some("y")));
+        choose(&mut a, 1, None);
+        let kept: Vec<(usize, Option<String>)> = item_decisions(&a).map(|d| (d.first_idx, d.option)).collect();
+        assert_eq!(kept, [(0, Some("y".into())), (1, None)]);
+    }
+
+    #[test]
+    fn item_decisions_replace_pending_ones() {
+        let mut a = item("a.TextGrid");
+        choose(&mut a, 0, Some(0));
+        let mut pending: Vec<MarkDecision> = item_decisions(&a).collect();
+        pending[0].option = Some("y".into());
+        pending.push(MarkDecision { file: "b.TextGrid".into(), ..pending[0].clone() });
+        let merged = merge_decisions(&[a], &pending);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[&("a.TextGrid".into(), "r".into(), 0)].option.as_deref(), Some("x"));
+        assert_eq!(merged[&("b.TextGrid".into(), "r".into(), 0)].option.as_deref(), Some("y"));
+    }
+
+    #[test]
+    fn restores_decisions_on_a_rescan() {
+        let mut before = item("a.TextGrid");
+        choose(&mut before, 0, None);
+        choose(&mut before, 1, Some(1));
+        let mut decisions = decisions_of(&before);
+        let mut rescanned = item("a.TextGrid");
+        assert!(restore_decisions(&mut rescanned, &mut decisions).is_empty());
+        assert_eq!(rescanned.selected_options, [None, Some(1), None]);
+        assert_eq!(rescanned.decided, [true, true, false]);
+        assert!(rescanned.dirty);
+        assert!(decisions.is_empty());
+    }
+
+    #[test]
+    fn reports_a_removed_option() {
+        let mut before = item("a.TextGrid");
+        choose(&mut before, 1, Some(0));
+        let mut decisions = decisions_of(&before);
+        let mut rescanned = item("a.TextGrid");
+        rescanned.replace_options[1] = vec!["y".into()];
+        let lost = restore_decisions(&mut rescanned, &mut decisions);
+        assert_eq!(lost.len(), 1);
+        assert_eq!((lost[0].0.option.as_deref(), lost[0].1), (Some("x"), LostReason::OptionRemoved));
+        assert_eq!(rescanned.selected_options, vec![None; 3]);
+        assert_eq!(rescanned.decided, vec![false; 3]);
+        assert!(!rescanned.dirty);
+        assert!(decisions.is_empty());
+    }
+
+    #[test]
+    fn reports_a_changed_mark() {
+        let mut before = item("a.TextGrid");
+        choose(&mut before, 1, Some(0));
+        let mut decisions = decisions_of(&before);
+        let mut rescanned = item("a.TextGrid");
+        rescanned.tg_content.items[0].intervals[1].xmin = 0.5.into();
+        let lost = restore_decisions(&mut rescanned, &mut decisions);
+        assert_eq!(lost.iter().map(|l| l.1).collect::<Vec<_>>(), [LostReason::MarkChanged]);
+        assert_eq!(rescanned.selected_options, vec![None; 3]);
+        assert!(decisions.is_empty());
+    }
+
+    #[test]
+    fn drops_stale_decisions_of_scanned_files_and_rules() {
+        let mut a = item("a.TextGrid");
+        choose(&mut a, 2, Some(1));
+        let mut b = item("b.TextGrid");
+        choose(&mut b, 0, Some(1));
+        let mut other_rule = item("a.TextGrid");
+        other_rule.found_mark_rules = vec!["s".into(); 3];
+        choose(&mut other_rule, 0, Some(0));
+        let mut decisions = merge_decisions(&[a, b, other_rule], &[]);
+        // a.TextGrid no longer has any marks of `r`, b.TextGrid was not scanned and `s` was not scanned with.
+        let scanned_files = HashSet::from([PathBuf::from("a.TextGrid")]);
+        let lost = drop_stale_decisions(&mut decisions, &scanned_files, &HashSet::from(["r"]));
+        assert_eq!(lost.len(), 1);
+        assert_eq!((lost[0].0.key(), lost[0].1), (("a.TextGrid".into(), "r".into(), 2), LostReason::MarkGone));
+        let mut kept: Vec<DecisionKey> = decisions.into_keys().collect();
+        kept.sort();
+        assert_eq!(kept, [("a.TextGrid".into(), "s".into(), 0), ("b.TextGrid".into(), "r".into(), 0)]);
+    }
+}
@@ -0,0 +1,56 @@
+//! TextGrid parsing and writing, rule matching and replacement, and the project files
+//! and scanned items built on them, shared by the DiffSinger synonym label replacer app
+//! and its command line tool.
+
+pub mod files;
+pub mod item;
+pub mod pattern;
+pub mod project;
+pub mod replace;
+pub mod rule;
+pub mod textgrid;
+
+pub use files::{find_textgrids, resolve_wav, ScanError, TextGridFilter};
+pub use item::{
+    apply_selected_options, drop_stale_decisions, item_decisions, mark_content_hash, merge_decisions, restore_decisions, scan_textgrid, write_item,
+    DecisionKey, Decisions, ItemRecord, LostReason, MarkDecision,
+};
+pub use pattern::{parse_class_definition, PatternError, PhonemeClasses};
+pub use project::{read_project_file, write_project_file, ItemOrder, ProjectState};
+pub use replace::{apply_marks, parse_replace_option, MarkChoice, ReplaceError};
+pub use rule::{find_marks, resolve_target_tier, resolve_tiers, CompiledRule, ReplaceRule, SpanMode, TargetTier, WordPosition};
+pub use textgrid::{
+    encode_textgrid, parse_textgrid, parse_textgrid_str, textgrid_to_string, write_textgrid, EncodeError, SourceEncoding, TextGrid,
+    TextGridError, TextGridInterval, TextGridTier,
+};
+
+/// Any error of the crate, for callers that do not need to tell them apart.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    TextGrid(Box<TextGridError>),
+    #[error(transparent)]
+    Pattern(#[from] PatternError),
+    #[error(transparent)]
+    Replace(#[from] ReplaceError),
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+    #[error(transparent)]
+    Scan(#[from] ScanError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl From<TextGridError> for Error {
+    fn from(e: TextGridError) -> Self {
+        Error::TextGrid(Box::new(e))
+    }
+}
+
+impl From<Box<TextGridError>> for Error {
+    fn from(e: Box<TextGridError>) -> Self {
+        Error::TextGrid(e)
+    }
+}
@@ -0,0 +1,363 @@
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+
+/// Named sets of labels, referenced as `@NAME` in search terms.
+pub type PhonemeClasses = BTreeMap<String, Vec<String>>;
+
+/// Why a search term, context, word filter or phoneme class could not be read.
+#[derive(Debug, thiserror::Error)]
+pub enum PatternError {
+    #[error("Unknown phoneme class @{0}")]
+    UnknownClass(String),
+    #[error("Invalid regex {token}: {source}")]
+    InvalidRegex { token: String, source: regex::Error },
+    #[error("Invalid pattern {token}: {source}")]
+    InvalidGlob { token: String, source: regex::Error },
+    #[error("Empty set {0}")]
+    EmptySet(String),
+    #[error("Set {0} is not closed, sets are written without spaces like {{a,e}}")]
+    UnclosedSet(String),
+    #[error("Empty context")]
+    EmptyContext,
+    #[error("Phoneme class must be written as NAME = a e i")]
+    ClassSyntax,
+    #[error("Invalid phoneme class name {0}")]
+    ClassName(String),
+    #[error("Phoneme class {0} has no members")]
+    EmptyClass(String),
+}
+
+fn class_members<'a>(name: &str, classes: &'a PhonemeClasses) -> Result<&'a Vec<String>, PatternError> {
+    classes.get(name).ok_or_else(|| PatternError::UnknownClass(name.to_string()))
+}
+
+/// Pattern for a single interval label within a search term.
+///
+/// - `*` matches any label.
+/// - `{a,e,i}` matches any of the listed labels. Terms are split on whitespace first,
+///   so sets are written without spaces.
+/// - `@NAME` matches any label of the project's phoneme class `NAME`, classes can
+///   also be listed in a set (`{@VOWELS,y}`).
+/// - `/re/` matches labels the regular expression finds a match in, so anchor it
+///   (`/^a.*/`) to match whole labels.
+/// - A token containing `*` or `?` is a glob over the whole label (`a*` matches `a1`, `ai`).
+/// - Anything else matches the label exactly.
+#[derive(Clone, Debug)]
+pub enum TokenPattern {
+    Exact(String),
+    Any,
+    Set(HashSet<String>),
+    Regex(Regex),
+}
+
+impl TokenPattern {
+    pub fn parse(token: &str, classes: &PhonemeClasses) -> Result<Self, PatternError> {
+        if token == "*" {
+            Ok(TokenPattern::Any)
+        } else if let Some(name) = token.strip_prefix('@') {
+            let members = class_members(name, classes)?;
+            Ok(TokenPattern::Set(members.iter().cloned().collect()))
+        } else if token.len() > 2 && token.starts_with('/') && token.ends_with('/') {
+            Regex::new(&token[1..token.len() - 1])
+                .map(TokenPattern::Regex)
+                .map_err(|source| PatternError::InvalidRegex { token: token.to_string(), source })
+        } else if token.len() > 2 && token.starts_with('{') && token.ends_with('}') {
+            let mut set = HashSet::new();
+            for member in token[1..token.len() - 1].split(",").map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                match member.strip_prefix('@') {
+                    Some(name) => set.extend(class_members(name, classes)?.iter().cloned()),
+                    None => {
+                        set.insert(member.to_string());
+                    }
+                }
+            }
+            if set.is_empty() {
+                return Err(PatternError::EmptySet(token.to_string()));
+            }
+            Ok(TokenPattern::Set(set))
+        } else if token.starts_with('{') {
+            // A set broken up by a space, such as `{a,` of `{a, e}`.
+            Err(PatternError::UnclosedSet(token.to_string()))
+        } else if token.contains(['*', '?']) {
+            let mut re = String::from("^");
+            for c in token.chars() {
+                match c {
+                    '*' => re.push_str(".*"),
+                    '?' => re.push('.'),
+                    _ => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+                }
+            }
+            re.push('$');
+            Regex::new(&re)
+                .map(TokenPattern::Regex)
+                .map_err(|source| PatternError::InvalidGlob { token: token.to_string(), source })
+        } else {
+            Ok(TokenPattern::Exact(token.to_string()))
+        }
+    }
+
+    pub fn matches(&self, label: &str) -> bool {
+        match self {
+            TokenPattern::Exact(text) => text == label,
+            TokenPattern::Any => true,
+            TokenPattern::Set(set) => set.contains(label),
+            TokenPattern::Regex(re) => re.is_match(label),
+        }
+    }
+}
+
+/// A whitespace separated search term, one pattern per interval.
+#[derive(Clone, Debug)]
+pub struct TermPattern {
+    tokens: Vec<TokenPattern>,
+}
+
+impl TermPattern {
+    pub fn parse(term: &str, classes: &PhonemeClasses) -> Result<Self, PatternError> {
+        let tokens = term
+            .split_whitespace()
+            .map(|token| TokenPattern::parse(token, classes))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TermPattern { tokens })
+    }
+
+    fn exact_tokens(&self) -> Option<Vec<String>> {
+        self.tokens
+            .iter()
+            .map(|t| match t {
+                TokenPattern::Exact(text) => Some(text.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn matches<'a>(&self, labels: impl IntoIterator<Item = &'a str>) -> bool {
+        let mut labels = labels.into_iter();
+        self.tokens
+            .iter()
+            .all(|t| labels.next().is_some_and(|label| t.matches(label)))
+            && labels.next().is_none()
+    }
+}
+
+/// The search terms of a rule, ready for matching. Terms without any pattern
+/// syntax are looked up in a set, the rest are tried one by one.
+#[derive(Clone, Debug, Default)]
+pub struct CompiledTerms {
+    exact: HashSet<Vec<String>>,
+    patterns: Vec<TermPattern>,
+}
+
+impl CompiledTerms {
+    pub fn compile(terms: &[String], classes: &PhonemeClasses) -> Result<Self, PatternError> {
+        let mut compiled = CompiledTerms::default();
+        for term in terms {
+            let pattern = TermPattern::parse(term, classes)?;
+            match pattern.exact_tokens() {
+                Some(tokens) => {
+                    compiled.exact.insert(tokens);
+                }
+                None => compiled.patterns.push(pattern),
+            }
+        }
+        Ok(compiled)
+    }
+
+    pub fn matches(&self, labels: &[&str]) -> bool {
+        (!self.exact.is_empty() && self.exact.contains(&labels.iter().map(|s| s.to_string()).collect::<Vec<String>>()))
+            || self.patterns.iter().any(|p| p.matches(labels.iter().copied()))
+    }
+}
+
+/// Parses a class definition of the form `NAME = a e i`, members may also be
+/// separated by commas.
+pub fn parse_class_definition(definition: &str) -> Result<(String, Vec<String>), PatternError> {
+    let (name, members) = definition.split_once('=').ok_or(PatternError::ClassSyntax)?;
+    let name = name.trim().trim_start_matches('@');
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(PatternError::ClassName(name.to_string()));
+    }
+    let members: Vec<String> = members
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+    if members.is_empty() {
+        return Err(PatternError::EmptyClass(name.to_string()));
+    }
+    Ok((name.to_string(), members))
+}
+
+/// Word and utterance boundaries of a tier. Positions lie between intervals,
+/// position `p` is right before interval `p`, so a tier of `n` intervals has
+/// positions `0..=n`.
+#[derive(Clone, Debug, Default)]
+pub struct TierBoundaries {
+    pub word: Vec<bool>,
+    pub utterance_start: usize,
+    pub utterance_end: usize,
+}
+
+impl TierBoundaries {
+    pub fn is_word_boundary(&self, pos: usize) -> bool {
+        self.word.get(pos).copied().unwrap_or(true)
+    }
+
+    pub fn is_utterance_boundary(&self, pos: usize) -> bool {
+        pos <= self.utterance_start || pos >= self.utterance_end
+    }
+}
+
+#[derive(Clone, Debug)]
+enum ContextToken {
+    Label(TokenPattern),
+    WordBoundary,
+    UtteranceBoundary,
+}
+
+/// A context next to a match, written like a search term. Besides label patterns it
+/// may contain `#` for a word boundary and `##` for an utterance boundary, which
+/// take up no interval. Left contexts are read towards the match (`# y` is a `y`
+/// starting a word right before the match), right contexts away from it.
+#[derive(Clone, Debug)]
+pub struct ContextPattern {
+    tokens: Vec<ContextToken>,
+}
+
+impl ContextPattern {
+    pub fn parse(context: &str, classes: &PhonemeClasses) -> Result<Self, PatternError> {
+        let tokens = context
+            .split_whitespace()
+            .map(|token| match token {
+                "#" => Ok(ContextToken::WordBoundary),
+                "##" => Ok(ContextToken::UtteranceBoundary),
+                _ => TokenPattern::parse(token, classes).map(ContextToken::Label),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if tokens.is_empty() {
+            return Err(PatternError::EmptyContext);
+        }
+        Ok(ContextPattern { tokens })
+    }
+
+    fn matches_token(token: &ContextToken, labels: &[&str], pos: usize, label_idx: Option<usize>, boundaries: &TierBoundaries) -> bool {
+        match token {
+            ContextToken::WordBoundary => boundaries.is_word_boundary(pos),
+            ContextToken::UtteranceBoundary => boundaries.is_utterance_boundary(pos),
+            ContextToken::Label(t) => label_idx.and_then(|i| labels.get(i)).is_some_and(|label| t.matches(label)),
+        }
+    }
+
+    /// Checks the context as the left neighbour of a match starting at `start`.
+    pub fn matches_before(&self, labels: &[&str], start: usize, boundaries: &TierBoundaries) -> bool {
+        let mut pos = start;
+        for token in self.tokens.iter().rev() {
+            if !Self::matches_token(token, labels, pos, pos.checked_sub(1), boundaries) {
+                return false;
+            }
+            if let ContextToken::Label(_) = token {
+                pos -= 1;
+            }
+        }
+        true
+    }
+
+    /// Checks the context as the right neighbour of a match ending right before `end`.
+    pub fn matches_after(&self, labels: &[&str], end: usize, boundaries: &TierBoundaries) -> bool {
+        let mut pos = end;
+        for token in self.tokens.iter() {
+            if !Self::matches_token(token, labels, pos, Some(pos), boundaries) {
+                return false;
+            }
+            if let ContextToken::Label(_) = token {
+                pos += 1;
+            }
+        }
+        true
+    }
+}
+
+/// The left and right contexts of a rule. A match needs any one of the left contexts
+/// and any one of the right contexts, an empty side always holds.
+#[derive(Clone, Debug, Default)]
+pub struct CompiledContexts {
+    left: Vec<ContextPattern>,
+    right: Vec<ContextPattern>,
+}
+
+impl CompiledContexts {
+    pub fn compile(left: &[String], right: &[String], classes: &PhonemeClasses) -> Result<Self, PatternError> {
+        Ok(CompiledContexts {
+            left: left.iter().map(|c| ContextPattern::parse(c, classes)).collect::<Result<_, _>>()?,
+            right: right.iter().map(|c| ContextPattern::parse(c, classes)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn matches(&self, labels: &[&str], start: usize, end: usize, boundaries: &TierBoundaries) -> bool {
+        (self.left.is_empty() || self.left.iter().any(|c| c.matches_before(labels, start, boundaries)))
+            && (self.right.is_empty() || self.right.iter().any(|c| c.matches_after(labels, end, boundaries)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classes() -> PhonemeClasses {
+        PhonemeClasses::from([("VOWELS".to_string(), vec!["a".to_string(), "i".to_string()])])
+    }
+
+    fn token(pattern: &str) -> TokenPattern {
+        TokenPattern::parse(pattern, &classes()).unwrap()
+    }
+
+    #[test]
+    fn token_kinds() {
+        assert!(token("a").matches("a") && !token("a").matches("ai"));
+        assert!(token("*").matches("anything"));
+        assert!(token("{a,e}").matches("e") && !token("{a,e}").matches("i"));
+        assert!(token("@VOWELS").matches("i") && !token("@VOWELS").matches("e"));
+        assert!(token("{@VOWELS,y}").matches("y"));
+        assert!(token("a*").matches("ai") && !token("a*").matches("ia"));
+        assert!(token("/^a.$/").matches("ai") && !token("/^a.$/").matches("a"));
+    }
+
+    #[test]
+    fn token_errors() {
+        assert!(matches!(TokenPattern::parse("@NASALS", &classes()), Err(PatternError::UnknownClass(_))));
+        assert!(matches!(TokenPattern::parse("/(/", &classes()), Err(PatternError::InvalidRegex { .. })));
+        assert!(matches!(TokenPattern::parse("{,}", &classes()), Err(PatternError::EmptySet(_))));
+        assert!(matches!(TokenPattern::parse("{a,", &classes()), Err(PatternError::UnclosedSet(_))));
+        assert!(matches!(TermPattern::parse("{a, e} n", &classes()), Err(PatternError::UnclosedSet(_))));
+    }
+
+    #[test]
+    fn terms_match_sequences() {
+        let terms = CompiledTerms::compile(&["a i".to_string(), "@VOWELS *".to_string()], &classes()).unwrap();
+        assert!(terms.matches(&["a", "i"]));
+        assert!(terms.matches(&["i", "n"]));
+        assert!(!terms.matches(&["n", "a"]));
+    }
+
+    #[test]
+    fn class_definitions() {
+        assert_eq!(parse_class_definition("@NASALS = n, ng m").unwrap(), ("NASALS".to_string(), vec!["n".into(), "ng".into(), "m".into()]));
+        assert!(matches!(parse_class_definition("NASALS n"), Err(PatternError::ClassSyntax)));
+        assert!(matches!(parse_class_definition("NA-SALS = n"), Err(PatternError::ClassName(_))));
+        assert!(matches!(parse_class_definition("NASALS ="), Err(PatternError::EmptyClass(_))));
+    }
+
+    #[test]
+    fn contexts_with_boundaries() {
+        let labels = ["SP", "y", "a", "n", "SP"];
+        // Words: [y a] [n].
+        let boundaries = TierBoundaries { word: vec![true, true, false, true, true, true], utterance_start: 1, utterance_end: 4 };
+        let left = ContextPattern::parse("# y", &classes()).unwrap();
+        assert!(left.matches_before(&labels, 2, &boundaries));
+        assert!(!ContextPattern::parse("#", &classes()).unwrap().matches_before(&labels, 2, &boundaries));
+        let right = ContextPattern::parse("n ##", &classes()).unwrap();
+        assert!(right.matches_after(&labels, 3, &boundaries));
+        assert!(!right.matches_after(&labels, 2, &boundaries));
+        assert!(matches!(ContextPattern::parse(" ", &classes()), Err(PatternError::EmptyContext)));
+    }
+}
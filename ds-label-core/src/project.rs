@@ -0,0 +1,129 @@
+use crate::files::{ScanError, TextGridFilter};
+use crate::item::{Decisions, MarkDecision};
+use crate::pattern::{PatternError, PhonemeClasses};
+use crate::rule::{resolve_target_tier, resolve_tiers, CompiledRule, ReplaceRule};
+use crate::textgrid::TextGrid;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How the items of a session are ordered. Ties keep the path order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemOrder {
+    /// Natural order of the path relative to the TextGrid folder, `2` before `10`.
+    #[default]
+    Path,
+    /// Most marks first.
+    MarkCount,
+    /// Most recently modified file first.
+    Modified,
+    /// Grouped by the earliest rule of the project that marked the item.
+    Rule,
+    /// Most undecided marks first.
+    Undecided,
+}
+
+/// The folders, rules and settings of a project, as saved in its project file.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ProjectState {
+    pub tg_folder: Option<PathBuf>,
+    pub wav_folder: Option<PathBuf>,
+    pub rules: Vec<ReplaceRule>,
+    pub selected_rule_idx: Option<i32>,
+    pub selected_term_idx: Option<i32>,
+    pub selected_opt_idx: Option<i32>,
+    pub word_tier_names: Vec<String>,
+    pub phone_tier_names: Vec<String>,
+    pub phoneme_classes: PhonemeClasses,
+    pub silence_marks: Vec<String>,
+    pub active_rule_idxs: Vec<usize>,
+    pub include_patterns: Vec<String>,
+    pub exclude_patterns: Vec<String>,
+    pub recursive_scan: bool,
+    pub item_order: ItemOrder,
+}
+
+impl Default for ProjectState {
+    fn default() -> Self {
+        ProjectState {
+            tg_folder: None,
+            wav_folder: None,
+            rules: Vec::new(),
+            selected_rule_idx: None,
+            selected_term_idx: None,
+            selected_opt_idx: None,
+            word_tier_names: vec!["words".into(), "word".into()],
+            phone_tier_names: vec!["phones".into(), "phone".into(), "phonemes".into()],
+            phoneme_classes: PhonemeClasses::new(),
+            silence_marks: vec!["SP".into(), "AP".into()],
+            active_rule_idxs: Vec::new(),
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            recursive_scan: false,
+            item_order: ItemOrder::Path,
+        }
+    }
+}
+
+impl ProjectState {
+    /// [`resolve_tiers`] with the project's tier names.
+    pub fn resolve_tiers(&self, tg: &TextGrid) -> Option<(Option<usize>, usize)> {
+        resolve_tiers(tg, &self.word_tier_names, &self.phone_tier_names)
+    }
+
+    pub fn compile_session_rules(&self) -> Result<Vec<CompiledRule<'_>>, PatternError> {
+        self.session_rule_idxs()
+            .iter()
+            .map(|&i| CompiledRule::compile(&self.rules[i], &self.phoneme_classes, &self.silence_marks))
+            .collect()
+    }
+
+    pub fn textgrid_filter(&self) -> Result<TextGridFilter, ScanError> {
+        TextGridFilter::new(&self.include_patterns, &self.exclude_patterns, self.recursive_scan)
+    }
+
+    /// The rules a session scans with, in project order. Without any rules ticked
+    /// for the session this is the selected rule.
+    pub fn session_rule_idxs(&self) -> Vec<usize> {
+        let mut rule_idxs: Vec<usize> = if self.active_rule_idxs.is_empty() {
+            self.selected_rule_idx.map(|i| i as usize).into_iter().collect()
+        } else {
+            self.active_rule_idxs.clone()
+        };
+        rule_idxs.retain(|&i| i < self.rules.len());
+        rule_idxs.sort();
+        rule_idxs.dedup();
+        rule_idxs
+    }
+
+    /// [`resolve_target_tier`] for `rule` on the project's tiers.
+    pub fn resolve_target_tier(&self, rule: &ReplaceRule, tg: &TextGrid) -> Option<usize> {
+        let (word_tier_idx, phone_tier_idx) = self.resolve_tiers(tg)?;
+        resolve_target_tier(&rule.target_tier, tg, word_tier_idx, phone_tier_idx)
+    }
+}
+
+/// Writes a project file holding the project and the review decisions not saved to
+/// the TextGrids yet, in file order.
+pub fn write_project_file(path: &Path, project: &ProjectState, decisions: &Decisions) -> Result<(), crate::Error> {
+    let mut keys: Vec<_> = decisions.keys().collect();
+    keys.sort();
+    let project_json = serde_json::to_string(project)?;
+    let decisions_json = serde_json::to_string(&keys.into_iter().map(|k| &decisions[k]).collect::<Vec<_>>())?;
+    fs::write(path, format!("{{\"app_state\": {}, \"decisions\": {}}}", project_json, decisions_json))?;
+    Ok(())
+}
+
+/// Reads a project file written by `write_project_file`. Files saved before decisions
+/// were kept have none.
+pub fn read_project_file(path: &Path) -> Result<(ProjectState, Vec<MarkDecision>), crate::Error> {
+    let file_contents = fs::read_to_string(path)?;
+    let json: serde_json::Value = serde_json::from_str(&file_contents)?;
+    let project = serde_json::from_value(json["app_state"].clone())?;
+    let decisions = match json.get("decisions") {
+        Some(decisions) => serde_json::from_value(decisions.clone())?,
+        None => Vec::new(),
+    };
+    Ok((project, decisions))
+}
@@ -0,0 +1,542 @@
+use crate::textgrid::{TextGrid, TextGridInterval, TextGridTier, TextGridTime};
+use std::cmp::min;
+use std::collections::HashMap;
+
+/// Why a replace option could not be read or applied.
+#[derive(Debug, thiserror::Error)]
+pub enum ReplaceError {
+    #[error("Insertion must be written as +LABEL(50ms), got {0}")]
+    InsertSyntax(String),
+    #[error("Invalid weight in {0}")]
+    InvalidWeight(String),
+    #[error("Empty replace option")]
+    EmptyOption,
+    #[error("Weights cannot be combined with deletions or insertions")]
+    WeightedEdit,
+    #[error("A replace option needs at least one token for the matched intervals")]
+    OnlyInsertions,
+    #[error("Mark is out of range of the tier")]
+    MarkOutOfRange,
+    #[error("Changing the number of intervals needs a match without skipped intervals")]
+    SkippedIntervals,
+    #[error("A replace option with deletions or insertions needs one other token for each of the {0} matched intervals")]
+    EditTokenCount(usize),
+    #[error("Deleting or inserting needs a match without skipped intervals")]
+    SkippedIntervalsInEdit,
+    #[error("Nothing to insert next to")]
+    NothingToInsertNextTo,
+    #[error("Cannot delete the only interval of a tier")]
+    DeleteOnlyInterval,
+    #[error("Deleted time can only go to inserted intervals")]
+    NoIntervalForDeletedTime,
+    #[error("Overlapping marks cannot both be replaced when one of them changes the timing")]
+    OverlappingRetime,
+}
+
+/// One token of a replace option. `*` and label tokens may carry a relative duration
+/// weight in parentheses, `a(0.7) i(0.3)`; tokens without one weigh 1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplaceToken {
+    /// `*`, keeps the label of the interval the token takes its time from.
+    Keep { weight: Option<f64> },
+    Label { text: String, weight: Option<f64> },
+    /// `-`, removes the interval and gives its time to the previous one.
+    Delete,
+    /// `+AP(50ms)`, a new interval of the given length at this position.
+    Insert { text: String, duration_ms: f64 },
+}
+
+impl ReplaceToken {
+    pub fn parse(token: &str) -> Result<Self, ReplaceError> {
+        if token == "-" {
+            return Ok(ReplaceToken::Delete);
+        }
+        if let Some(insert) = token.strip_prefix('+') {
+            let (text, duration_ms) = insert
+                .strip_suffix("ms)")
+                .and_then(|t| t.rsplit_once('('))
+                .and_then(|(text, ms)| ms.trim().parse::<f64>().ok().map(|ms| (text, ms)))
+                .filter(|(text, ms)| !text.is_empty() && ms.is_finite() && *ms > 0.0)
+                .ok_or_else(|| ReplaceError::InsertSyntax(token.to_string()))?;
+            return Ok(ReplaceToken::Insert { text: text.to_string(), duration_ms });
+        }
+        let (text, weight) = match token.strip_suffix(')').and_then(|t| t.rsplit_once('(')) {
+            Some((text, weight)) if !text.is_empty() => {
+                let weight = weight
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|w| w.is_finite() && *w > 0.0)
+                    .ok_or_else(|| ReplaceError::InvalidWeight(token.to_string()))?;
+                (text, Some(weight))
+            }
+            _ => (token, None),
+        };
+        Ok(if text == "*" {
+            ReplaceToken::Keep { weight }
+        } else {
+            ReplaceToken::Label { text: text.to_string(), weight }
+        })
+    }
+
+    fn weight(&self) -> Option<f64> {
+        match self {
+            ReplaceToken::Keep { weight } | ReplaceToken::Label { weight, .. } => *weight,
+            ReplaceToken::Delete | ReplaceToken::Insert { .. } => None,
+        }
+    }
+
+    fn is_edit(&self) -> bool {
+        matches!(self, ReplaceToken::Delete | ReplaceToken::Insert { .. })
+    }
+}
+
+/// The part of a tier a replacement retimed.
+#[derive(Clone, Copy, Debug)]
+pub struct Retimed {
+    pub xmin: f64,
+    pub xmax: f64,
+    /// Number of intervals added, negative if intervals were removed.
+    pub delta: isize,
+}
+
+pub fn parse_replace_option(option: &str) -> Result<Vec<ReplaceToken>, ReplaceError> {
+    let tokens = option
+        .split_whitespace()
+        .map(ReplaceToken::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    if tokens.is_empty() {
+        return Err(ReplaceError::EmptyOption);
+    }
+    if tokens.iter().any(|t| t.is_edit()) && tokens.iter().any(|t| t.weight().is_some()) {
+        return Err(ReplaceError::WeightedEdit);
+    }
+    if tokens.iter().all(|t| matches!(t, ReplaceToken::Insert { .. })) {
+        return Err(ReplaceError::OnlyInsertions);
+    }
+    Ok(tokens)
+}
+
+/// Applies a replace option to the intervals `mark_idxs` of `tier`.
+///
+/// An option with as many tokens as intervals and no weights only rewrites labels.
+/// Otherwise the matched intervals, which then have to be contiguous, are replaced by
+/// one interval per token over the same time span. Weighted options divide the span
+/// by weight. Without weights the leading boundaries are kept and the last interval
+/// is split evenly, or the trailing intervals are merged into the last token.
+///
+/// Options with `-` or `+LABEL(Nms)` instead need one other token per matched interval,
+/// see [`apply_edits`].
+///
+/// Returns the part of the tier that was retimed, if any.
+pub fn apply_replacement(
+    tier: &mut TextGridTier,
+    mark_idxs: &[usize],
+    tokens: &[ReplaceToken],
+) -> Result<Option<Retimed>, ReplaceError> {
+    let n = tokens.len();
+    let k = mark_idxs.len();
+    if n == 0 {
+        return Err(ReplaceError::EmptyOption);
+    }
+    if k == 0 || mark_idxs.iter().any(|&i| i >= tier.intervals.len()) {
+        return Err(ReplaceError::MarkOutOfRange);
+    }
+    if tokens.iter().any(|t| t.is_edit()) {
+        return apply_edits(tier, mark_idxs, tokens).map(Some);
+    }
+    if n == k && tokens.iter().all(|t| t.weight().is_none()) {
+        for (token, &idx) in tokens.iter().zip(mark_idxs) {
+            if let ReplaceToken::Label { text, .. } = token {
+                tier.intervals[idx].text = text.clone();
+            }
+        }
+        return Ok(None);
+    }
+    if mark_idxs.windows(2).any(|w| w[1] != w[0] + 1) {
+        return Err(ReplaceError::SkippedIntervals);
+    }
+    let first = mark_idxs[0];
+    let old = &tier.intervals[first..first + k];
+    let (xmin, xmax) = (old[0].xmin.value(), old[k - 1].xmax.value());
+    // Each new interval as (start, end, index of the old interval it takes its label from).
+    let mut spans: Vec<(TextGridTime, TextGridTime, usize)> = Vec::with_capacity(n);
+    if tokens.iter().any(|t| t.weight().is_some()) {
+        let total: f64 = tokens.iter().map(|t| t.weight().unwrap_or(1.0)).sum();
+        let mut start = old[0].xmin.clone();
+        let mut acc = 0.0;
+        for (j, token) in tokens.iter().enumerate() {
+            acc += token.weight().unwrap_or(1.0);
+            let end = if j == n - 1 { old[k - 1].xmax.clone() } else { TextGridTime::new(xmin + (xmax - xmin) * acc / total) };
+            let mid = (start.value() + end.value()) / 2.0;
+            let source = old.iter().position(|i| mid < i.xmax.value()).unwrap_or(k - 1);
+            spans.push((start, end.clone(), source));
+            start = end;
+        }
+    } else if n < k {
+        for (j, interval) in old.iter().enumerate().take(n - 1) {
+            spans.push((interval.xmin.clone(), interval.xmax.clone(), j));
+        }
+        spans.push((old[n - 1].xmin.clone(), old[k - 1].xmax.clone(), n - 1));
+    } else {
+        for (j, interval) in old.iter().enumerate().take(k - 1) {
+            spans.push((interval.xmin.clone(), interval.xmax.clone(), j));
+        }
+        let last = &old[k - 1];
+        let pieces = n - k + 1;
+        let step = (last.xmax.value() - last.xmin.value()) / pieces as f64;
+        let mut start = last.xmin.clone();
+        for p in 1..=pieces {
+            let end = if p == pieces { last.xmax.clone() } else { TextGridTime::new(last.xmin.value() + step * p as f64) };
+            spans.push((start, end.clone(), k - 1));
+            start = end;
+        }
+    }
+    let new_intervals: Vec<TextGridInterval> = tokens
+        .iter()
+        .zip(spans)
+        .map(|(token, (start, end, source))| TextGridInterval {
+            xmin: start,
+            xmax: end,
+            text: match token {
+                ReplaceToken::Label { text, .. } => text.clone(),
+                _ => old[source].text.clone(),
+            },
+        })
+        .collect();
+    tier.intervals.splice(first..first + k, new_intervals);
+    Ok(Some(Retimed { xmin, xmax, delta: n as isize - k as isize }))
+}
+
+/// Applies an option with deletions and insertions. Every token other than an insertion
+/// stands for one matched interval in order. An inserted interval takes its time from
+/// the start of the matched interval after it, or from the end of the one before it at
+/// the end of the match, at most half of that interval. A deleted interval's time then
+/// goes to the nearest interval before it that was not inserted, or after it at the
+/// start of the tier. Inserted intervals in between move along and keep their length.
+fn apply_edits(tier: &mut TextGridTier, mark_idxs: &[usize], tokens: &[ReplaceToken]) -> Result<Retimed, ReplaceError> {
+    let k = mark_idxs.len();
+    if tokens.iter().filter(|t| !matches!(t, ReplaceToken::Insert { .. })).count() != k {
+        return Err(ReplaceError::EditTokenCount(k));
+    }
+    if mark_idxs.windows(2).any(|w| w[1] != w[0] + 1) {
+        return Err(ReplaceError::SkippedIntervalsInEdit);
+    }
+    let first = mark_idxs[0];
+    let last = mark_idxs[k - 1];
+    let xmin = tier.intervals[first.saturating_sub(1)].xmin.value();
+    let xmax = tier.intervals[min(last + 1, tier.intervals.len() - 1)].xmax.value();
+    let len_before = tier.intervals.len();
+    // Parallel to the intervals while editing.
+    let mut inserted = vec![false; len_before];
+    let mut deleted = vec![false; len_before];
+    let mut pos = first;
+    let mut remaining = k;
+    for token in tokens {
+        match token {
+            ReplaceToken::Insert { text, duration_ms } => {
+                let take_from_next = remaining > 0;
+                let source = if take_from_next { pos } else { pos.checked_sub(1).ok_or(ReplaceError::NothingToInsertNextTo)? };
+                let source_interval = &mut tier.intervals[source];
+                let duration = (duration_ms / 1000.0).min((source_interval.xmax.value() - source_interval.xmin.value()) / 2.0);
+                let new_interval = if take_from_next {
+                    let split = TextGridTime::new(source_interval.xmin.value() + duration);
+                    let new_interval = TextGridInterval { xmin: source_interval.xmin.clone(), xmax: split.clone(), text: text.clone() };
+                    source_interval.xmin = split;
+                    new_interval
+                } else {
+                    let split = TextGridTime::new(source_interval.xmax.value() - duration);
+                    let new_interval = TextGridInterval { xmin: split.clone(), xmax: source_interval.xmax.clone(), text: text.clone() };
+                    source_interval.xmax = split;
+                    new_interval
+                };
+                tier.intervals.insert(pos, new_interval);
+                inserted.insert(pos, true);
+                deleted.insert(pos, false);
+                pos += 1;
+            }
+            ReplaceToken::Delete => {
+                deleted[pos] = true;
+                pos += 1;
+                remaining -= 1;
+            }
+            ReplaceToken::Label { text, .. } => {
+                tier.intervals[pos].text = text.clone();
+                pos += 1;
+                remaining -= 1;
+            }
+            ReplaceToken::Keep { .. } => {
+                pos += 1;
+                remaining -= 1;
+            }
+        }
+    }
+    while let Some(pos) = deleted.iter().position(|&d| d) {
+        if tier.intervals.len() == 1 {
+            return Err(ReplaceError::DeleteOnlyInterval);
+        }
+        let removed = tier.intervals.remove(pos);
+        inserted.remove(pos);
+        deleted.remove(pos);
+        let span = removed.xmax.value() - removed.xmin.value();
+        let shift = |interval: &mut TextGridInterval, by: f64| {
+            interval.xmin = TextGridTime::new(interval.xmin.value() + by);
+            interval.xmax = TextGridTime::new(interval.xmax.value() + by);
+        };
+        if let Some(receiver) = (0..pos).rev().find(|&i| !inserted[i]) {
+            for interval in tier.intervals[receiver + 1..pos].iter_mut() {
+                shift(interval, span);
+            }
+            tier.intervals[receiver].xmax = match tier.intervals.get(receiver + 1) {
+                Some(next) if receiver + 1 < pos => next.xmin.clone(),
+                _ => removed.xmax,
+            };
+        } else if let Some(receiver) = (pos..tier.intervals.len()).find(|&i| !inserted[i]) {
+            for interval in tier.intervals[pos..receiver].iter_mut() {
+                shift(interval, -span);
+            }
+            tier.intervals[receiver].xmin = if receiver > pos { tier.intervals[receiver - 1].xmax.clone() } else { removed.xmin };
+        } else {
+            return Err(ReplaceError::NoIntervalForDeletedTime);
+        }
+    }
+    Ok(Retimed { xmin, xmax, delta: tier.intervals.len() as isize - len_before as isize })
+}
+
+/// Moves the boundaries of `tier` that lie strictly inside `xmin..xmax` onto the
+/// nearest of `reference`, never letting an interval shrink to nothing. A boundary
+/// without a usable reference time is left where it is.
+fn snap_boundaries(tier: &mut TextGridTier, reference: &[TextGridTime], xmin: f64, xmax: f64) {
+    let inner: Vec<usize> = (0..tier.intervals.len().saturating_sub(1))
+        .filter(|&b| tier.intervals[b].xmax.value() > xmin && tier.intervals[b].xmax.value() < xmax)
+        .collect();
+    let (Some(&first), Some(&last)) = (inner.first(), inner.last()) else {
+        return;
+    };
+    let mut lower = tier.intervals[first].xmin.value();
+    let upper = tier.intervals[last + 1].xmax.value();
+    for (i, &b) in inner.iter().enumerate() {
+        let remaining = inner.len() - i - 1;
+        let original = tier.intervals[b].xmax.value();
+        let target = reference
+            .iter()
+            .filter(|c| c.value() > lower && c.value() < upper)
+            .filter(|c| reference.iter().filter(|d| d.value() > c.value() && d.value() < upper).count() >= remaining)
+            .min_by(|c, d| (c.value() - original).abs().total_cmp(&(d.value() - original).abs()))
+            .cloned();
+        if let Some(target) = target {
+            tier.intervals[b].xmax = target.clone();
+            tier.intervals[b + 1].xmin = target;
+        }
+        lower = tier.intervals[b].xmax.value();
+    }
+}
+
+/// Keeps the word tier in line with the phone tier after the span `xmin..xmax` of the
+/// target tier was retimed. Word boundaries follow the phones when phones were
+/// replaced, and new word boundaries are moved onto phone boundaries when words were.
+pub fn reconcile_word_tier(
+    tg: &mut TextGrid,
+    word_tier_idx: Option<usize>,
+    phone_tier_idx: usize,
+    target_tier_idx: usize,
+    xmin: f64,
+    xmax: f64,
+) {
+    let Some(word_tier_idx) = word_tier_idx.filter(|&w| w != phone_tier_idx) else {
+        return;
+    };
+    if target_tier_idx != phone_tier_idx && target_tier_idx != word_tier_idx {
+        return;
+    }
+    let phones = &tg.items[phone_tier_idx].intervals;
+    let reference: Vec<TextGridTime> = phones
+        .iter()
+        .map(|i| i.xmin.clone())
+        .chain(phones.last().map(|i| i.xmax.clone()))
+        .filter(|t| t.value() >= xmin && t.value() <= xmax)
+        .collect();
+    snap_boundaries(&mut tg.items[word_tier_idx], &reference, xmin, xmax);
+}
+
+/// A replace option chosen for a mark.
+#[derive(Clone, Copy, Debug)]
+pub struct MarkChoice<'a> {
+    pub tier_idx: usize,
+    pub mark_idxs: &'a [usize],
+    pub option: &'a str,
+}
+
+/// Builds a copy of `tg` with the chosen options applied. Marks are applied in tier
+/// order, shifting later marks by the intervals earlier ones added or removed, and the
+/// word tier is kept in line with retimed phones.
+pub fn apply_marks(
+    tg: &TextGrid,
+    word_tier_idx: Option<usize>,
+    phone_tier_idx: usize,
+    choices: &[MarkChoice],
+) -> Result<TextGrid, ReplaceError> {
+    let mut new_tg = tg.clone();
+    // Per tier, the shift of later interval indices and where the last retimed mark ended.
+    let mut tier_shifts: HashMap<usize, (isize, usize)> = HashMap::new();
+    let mut order: Vec<&MarkChoice> = choices.iter().collect();
+    order.sort_by_key(|c| (c.tier_idx, c.mark_idxs.first().copied()));
+    for choice in order {
+        let tokens = parse_replace_option(choice.option)?;
+        let first = *choice.mark_idxs.first().ok_or(ReplaceError::MarkOutOfRange)?;
+        let (shift, retimed_until) = tier_shifts.entry(choice.tier_idx).or_insert((0, 0));
+        if first < *retimed_until {
+            return Err(ReplaceError::OverlappingRetime);
+        }
+        let current_idxs: Vec<usize> = choice.mark_idxs.iter().map(|&j| (j as isize + *shift) as usize).collect();
+        let tier = new_tg.items.get_mut(choice.tier_idx).ok_or(ReplaceError::MarkOutOfRange)?;
+        if let Some(retimed) = apply_replacement(tier, &current_idxs, &tokens)? {
+            *shift += retimed.delta;
+            *retimed_until = choice.mark_idxs.last().unwrap() + 1;
+            reconcile_word_tier(&mut new_tg, word_tier_idx, phone_tier_idx, choice.tier_idx, retimed.xmin, retimed.xmax);
+        }
+    }
+    Ok(new_tg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier(bounds: &[f64], labels: &[&str]) -> TextGridTier {
+        TextGridTier {
+            intervals: labels
+                .iter()
+                .enumerate()
+                .map(|(i, text)| TextGridInterval { xmin: bounds[i].into(), xmax: bounds[i + 1].into(), text: text.to_string() })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn grid(tier: TextGridTier) -> TextGrid {
+        let mut tg = TextGrid::default();
+        tg.items.push(tier);
+        tg
+    }
+
+    fn replace(tier: &mut TextGridTier, mark_idxs: &[usize], option: &str) -> Result<Option<Retimed>, ReplaceError> {
+        apply_replacement(tier, mark_idxs, &parse_replace_option(option).unwrap())
+    }
+
+    fn spans(tier: &TextGridTier) -> Vec<(String, f64, f64)> {
+        tier.intervals
+            .iter()
+            .map(|i| (i.text.clone(), (i.xmin.value() * 1000.0).round() / 1000.0, (i.xmax.value() * 1000.0).round() / 1000.0))
+            .collect()
+    }
+
+    fn span(text: &str, xmin: f64, xmax: f64) -> (String, f64, f64) {
+        (text.to_string(), xmin, xmax)
+    }
+
+    #[test]
+    fn relabels_in_place() {
+        let mut t = tier(&[0.0, 1.0, 2.0], &["a", "b"]);
+        assert!(replace(&mut t, &[1], "c").unwrap().is_none());
+        assert_eq!(spans(&t), vec![span("a", 0.0, 1.0), span("c", 1.0, 2.0)]);
+    }
+
+    #[test]
+    fn rejects_empty_tokens() {
+        let mut t = tier(&[0.0, 1.0, 2.0], &["a", "b"]);
+        assert!(matches!(apply_replacement(&mut t, &[0, 1], &[]), Err(ReplaceError::EmptyOption)));
+        assert_eq!(spans(&t), vec![span("a", 0.0, 1.0), span("b", 1.0, 2.0)]);
+    }
+
+    #[test]
+    fn splits_by_weight() {
+        let mut t = tier(&[0.0, 1.0], &["ai"]);
+        replace(&mut t, &[0], "a(3) i(1)").unwrap();
+        assert_eq!(spans(&t), vec![span("a", 0.0, 0.75), span("i", 0.75, 1.0)]);
+    }
+
+    #[test]
+    fn deletion_goes_to_previous_interval() {
+        let mut t = tier(&[0.0, 1.0, 2.0, 3.0], &["a", "b", "c"]);
+        replace(&mut t, &[1], "-").unwrap();
+        assert_eq!(spans(&t), vec![span("a", 0.0, 2.0), span("c", 2.0, 3.0)]);
+    }
+
+    #[test]
+    fn deletion_at_tier_start_goes_to_next_interval() {
+        let mut t = tier(&[0.0, 1.0, 2.0], &["a", "b"]);
+        replace(&mut t, &[0], "-").unwrap();
+        assert_eq!(spans(&t), vec![span("b", 0.0, 2.0)]);
+    }
+
+    #[test]
+    fn insertion_before_deletion_keeps_its_duration() {
+        let mut t = tier(&[0.0, 1.0, 2.0, 3.0], &["a", "b", "c"]);
+        replace(&mut t, &[1], "+AP(100ms) -").unwrap();
+        assert_eq!(spans(&t), vec![span("a", 0.0, 1.9), span("AP", 1.9, 2.0), span("c", 2.0, 3.0)]);
+    }
+
+    #[test]
+    fn insertion_after_deletion_takes_time_from_the_match() {
+        let mut t = tier(&[0.0, 1.0, 2.0, 3.0], &["a", "b", "c"]);
+        replace(&mut t, &[1], "- +AP(100ms)").unwrap();
+        assert_eq!(spans(&t), vec![span("a", 0.0, 1.9), span("AP", 1.9, 2.0), span("c", 2.0, 3.0)]);
+    }
+
+    #[test]
+    fn insertion_and_deletion_at_tier_start() {
+        let mut t = tier(&[0.0, 1.0, 2.0], &["a", "b"]);
+        replace(&mut t, &[0], "+AP(100ms) -").unwrap();
+        assert_eq!(spans(&t), vec![span("AP", 0.0, 0.1), span("b", 0.1, 2.0)]);
+    }
+
+    #[test]
+    fn deleted_time_cannot_go_only_to_insertions() {
+        let mut t = tier(&[0.0, 1.0], &["a"]);
+        assert!(matches!(replace(&mut t, &[0], "+AP(100ms) -"), Err(ReplaceError::NoIntervalForDeletedTime)));
+    }
+
+    #[test]
+    fn insertion_is_capped_at_half_the_interval() {
+        let mut t = tier(&[0.0, 0.1], &["a"]);
+        replace(&mut t, &[0], "* +AP(100ms)").unwrap();
+        assert_eq!(spans(&t), vec![span("a", 0.0, 0.05), span("AP", 0.05, 0.1)]);
+    }
+
+    #[test]
+    fn rejects_wrong_token_count() {
+        let mut t = tier(&[0.0, 1.0, 2.0], &["a", "b"]);
+        assert!(matches!(replace(&mut t, &[0, 1], "- +AP(50ms)"), Err(ReplaceError::EditTokenCount(2))));
+    }
+
+    #[test]
+    fn applies_marks_in_order_with_shifts() {
+        let tg = grid(tier(&[0.0, 1.0, 2.0, 3.0, 4.0], &["a", "b", "c", "d"]));
+        let choices = [
+            MarkChoice { tier_idx: 0, mark_idxs: &[3], option: "e" },
+            MarkChoice { tier_idx: 0, mark_idxs: &[1], option: "b1 b2" },
+        ];
+        let new_tg = apply_marks(&tg, None, 0, &choices).unwrap();
+        let labels: Vec<&str> = new_tg.items[0].intervals.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(labels, ["a", "b1", "b2", "c", "e"]);
+        assert_eq!(tg.items[0].intervals.len(), 4);
+    }
+
+    #[test]
+    fn rejects_overlapping_retimed_marks() {
+        let tg = grid(tier(&[0.0, 1.0, 2.0, 3.0], &["a", "b", "c"]));
+        let choices = [
+            MarkChoice { tier_idx: 0, mark_idxs: &[0, 1], option: "x" },
+            MarkChoice { tier_idx: 0, mark_idxs: &[1, 2], option: "y z" },
+        ];
+        assert!(matches!(apply_marks(&tg, None, 0, &choices), Err(ReplaceError::OverlappingRetime)));
+    }
+
+    #[test]
+    fn parses_insert_tokens() {
+        assert!(parse_replace_option("+AP").is_err());
+        assert!(parse_replace_option("").is_err());
+        assert!(parse_replace_option("a(0.5) -").is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::pattern::{CompiledContexts, CompiledTerms, PatternError, PhonemeClasses, TierBoundaries, TokenPattern};
+use crate::textgrid::{TextGrid, TextGridInterval};
+use std::cmp::min;
+
+/// The tier a rule searches and rewrites. Phones and words follow the project's tier
+/// names, any other tier is looked up by its own name.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetTier {
+    #[default]
+    Phones,
+    Words,
+    Named(String),
+}
+
+impl TargetTier {
+    pub fn from_name(name: &str) -> Self {
+        match name.trim().to_lowercase().as_str() {
+            "" | "phones" => TargetTier::Phones,
+            "words" => TargetTier::Words,
+            _ => TargetTier::Named(name.trim().to_string()),
+        }
+    }
+}
+
+/// Where a match has to sit within its word.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WordPosition {
+    #[default]
+    Any,
+    Start,
+    End,
+    Whole,
+}
+
+/// Whether a multi-interval match may run over word boundaries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpanMode {
+    #[default]
+    Anywhere,
+    WithinWord,
+    CrossBoundary,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ReplaceRule {
+    pub rule_name: String,
+    pub term_seq_length: usize,
+    pub search_terms: Vec<String>,
+    pub replace_options: Vec<String>,
+    #[serde(default)]
+    pub target_tier: TargetTier,
+    #[serde(default)]
+    pub left_contexts: Vec<String>,
+    #[serde(default)]
+    pub right_contexts: Vec<String>,
+    #[serde(default)]
+    pub word_filter: Vec<String>,
+    #[serde(default)]
+    pub word_position: WordPosition,
+    #[serde(default)]
+    pub span_mode: SpanMode,
+    #[serde(default)]
+    pub skip_silence: bool,
+}
+
+/// A rule with its search terms and contexts compiled against a project's phoneme
+/// classes, along with the project's silence marks.
+pub struct CompiledRule<'a> {
+    pub rule: &'a ReplaceRule,
+    terms: CompiledTerms,
+    contexts: CompiledContexts,
+    words: Vec<TokenPattern>,
+    silence_marks: &'a [String],
+}
+
+impl<'a> CompiledRule<'a> {
+    pub fn compile(rule: &'a ReplaceRule, classes: &PhonemeClasses, silence_marks: &'a [String]) -> Result<Self, PatternError> {
+        Ok(CompiledRule {
+            rule,
+            silence_marks,
+            terms: CompiledTerms::compile(&rule.search_terms, classes)?,
+            contexts: CompiledContexts::compile(&rule.left_contexts, &rule.right_contexts, classes)?,
+            words: rule.word_filter.iter().map(|w| TokenPattern::parse(w, classes)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Checks a word against the rule's word filter, either as a whole or by the part
+    /// before `:` for labels like `word:pinyin`.
+    fn matches_word(&self, word: &str) -> bool {
+        self.words.is_empty()
+            || self.words.iter().any(|w| w.matches(word) || w.matches(word.split(":").next().unwrap()))
+    }
+
+    /// Empty labels always count as silence.
+    fn is_silence(&self, label: &str) -> bool {
+        let label = label.trim();
+        label.is_empty() || self.silence_marks.iter().any(|m| m == label)
+    }
+
+    fn matches_span(&self, start: usize, end: usize, boundaries: &TierBoundaries) -> bool {
+        let mut inner = start + 1..end;
+        match self.rule.span_mode {
+            SpanMode::Anywhere => true,
+            SpanMode::WithinWord => !inner.any(|q| boundaries.is_word_boundary(q)),
+            SpanMode::CrossBoundary => inner.any(|q| boundaries.is_word_boundary(q)),
+        }
+    }
+
+    fn matches_word_position(&self, start: usize, end: usize, boundaries: &TierBoundaries) -> bool {
+        match self.rule.word_position {
+            WordPosition::Any => true,
+            WordPosition::Start => boundaries.is_word_boundary(start),
+            WordPosition::End => boundaries.is_word_boundary(end),
+            WordPosition::Whole => boundaries.is_word_boundary(start) && boundaries.is_word_boundary(end),
+        }
+    }
+}
+
+/// Picks the word and phone tiers of a TextGrid. Tiers with one of the given names win,
+/// otherwise the remaining interval tiers are taken in order, words first.
+/// A TextGrid with a single interval tier uses it for both.
+pub fn resolve_tiers(tg: &TextGrid, word_tier_names: &[String], phone_tier_names: &[String]) -> Option<(Option<usize>, usize)> {
+    let named_words = tg.find_interval_tier(word_tier_names);
+    let named_phones = tg.find_interval_tier(phone_tier_names);
+    let mut others = tg
+        .interval_tier_idxs()
+        .filter(|&i| Some(i) != named_words && Some(i) != named_phones);
+    let word_tier_idx = named_words.or_else(|| others.next());
+    let phone_tier_idx = named_phones.or_else(|| others.next()).or(word_tier_idx)?;
+    Some((word_tier_idx, phone_tier_idx))
+}
+
+/// Picks the tier `target` refers to, or `None` if the TextGrid does not have it.
+pub fn resolve_target_tier(target: &TargetTier, tg: &TextGrid, word_tier_idx: Option<usize>, phone_tier_idx: usize) -> Option<usize> {
+    match target {
+        TargetTier::Phones => Some(phone_tier_idx),
+        TargetTier::Words => word_tier_idx,
+        TargetTier::Named(name) => tg.find_interval_tier(std::slice::from_ref(name)),
+    }
+}
+
+/// Finds the matches of a rule on the tier `target_tier_idx`, as the interval indices
+/// of each match and a title showing it in its context.
+pub fn find_marks(
+    compiled: &CompiledRule,
+    tg: &TextGrid,
+    word_tier_idx: Option<usize>,
+    target_tier_idx: usize,
+) -> (Vec<Vec<usize>>, Vec<String>) {
+    let rule = compiled.rule;
+    let mut found_mark_idxs = Vec::new();
+    let mut found_mark_titles = Vec::new();
+    let tg_words: &[TextGridInterval] = word_tier_idx
+        .filter(|&idx| idx != target_tier_idx)
+        .map_or(&[], |idx| &tg.items[idx].intervals);
+    let tg_phones = &tg.items[target_tier_idx].intervals;
+    let mut corr_words = Vec::new();
+    let mut i = 0;
+    for (w_i, word) in tg_words.iter().enumerate() {
+        while i < tg_phones.len() {
+            if (word.xmax.value().min(tg_phones[i].xmax.value()) - word.xmin.value().max(tg_phones[i].xmin.value())) / (tg_phones[i].xmax.value() - tg_phones[i].xmin.value()) > 0.8 {
+                corr_words.push((w_i, word.text.clone()));
+                i += 1;
+            } else {
+                break;
+            }
+        }
+    }
+    let aligned = !tg_words.is_empty() && corr_words.len() == tg_phones.len();
+    let labels: Vec<&str> = tg_phones.iter().map(|p| p.text.as_str()).collect();
+    let word_labels: Option<Vec<&str>> = if word_tier_idx == Some(target_tier_idx) {
+        Some(labels.clone())
+    } else if aligned {
+        Some(corr_words.iter().map(|w| w.1.as_str()).collect())
+    } else {
+        None
+    };
+    let is_word_boundary = |p: usize| -> bool {
+        if p == 0 || p == labels.len() || word_tier_idx == Some(target_tier_idx) {
+            true
+        } else if aligned {
+            corr_words[p - 1].0 != corr_words[p].0
+        } else {
+            compiled.is_silence(labels[p - 1]) || compiled.is_silence(labels[p])
+        }
+    };
+    // Matching runs on a view of the tier, which leaves out silence if the rule skips it.
+    let view: Vec<usize> = (0..labels.len())
+        .filter(|&i| !rule.skip_silence || !compiled.is_silence(labels[i]))
+        .collect();
+    let view_labels: Vec<&str> = view.iter().map(|&i| labels[i]).collect();
+    let boundaries = TierBoundaries {
+        word: (0..=view.len())
+            .map(|q| q == 0 || q == view.len() || (view[q - 1] + 1..=view[q]).any(is_word_boundary))
+            .collect(),
+        utterance_start: view_labels.iter().position(|l| !compiled.is_silence(l)).unwrap_or(view.len()),
+        utterance_end: view_labels.iter().rposition(|l| !compiled.is_silence(l)).map_or(0, |q| q + 1),
+    };
+    if rule.term_seq_length >= 1 && view.len() >= rule.term_seq_length {
+        for (i, label_win) in view_labels.windows(rule.term_seq_length).enumerate() {
+            let mark_idxs = &view[i..i + rule.term_seq_length];
+            if compiled.terms.matches(label_win)
+                && compiled.contexts.matches(&view_labels, i, i + rule.term_seq_length, &boundaries)
+                && compiled.matches_word_position(i, i + rule.term_seq_length, &boundaries)
+                && compiled.matches_span(i, i + rule.term_seq_length, &boundaries)
+                && (compiled.words.is_empty()
+                    || word_labels.as_ref().is_some_and(|words| mark_idxs.iter().all(|&j| compiled.matches_word(words[j]))))
+            {
+                found_mark_idxs.push(mark_idxs.to_vec());
+                let mut title = String::new();
+                if aligned {
+                    let mut rel_words: Vec<(usize, String)> = mark_idxs.iter().map(|&j| corr_words[j].clone()).collect();
+                    rel_words.dedup_by_key(|x| x.0);
+                    title.push_str(format!("({}) ", rel_words.iter().map(|w| w.1.split(":").next().unwrap().to_string()).collect::<Vec<String>>().join(" ")).as_str());
+                }
+                for label in &view_labels[i.saturating_sub(2)..i] {
+                    title.push_str((label.to_string() + " ").as_str());
+                }
+                title.push_str(format!("[{}] ", label_win.join(" ")).as_str());
+                for label in &view_labels[min(i + rule.term_seq_length, view.len() - 1)..min(i + rule.term_seq_length + 2, view.len())] {
+                    title.push_str((label.to_string() + " ").as_str());
+                }
+                found_mark_titles.push(title.trim().to_string());
+            }
+        }
+    }
+    (found_mark_idxs, found_mark_titles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::textgrid::parse_textgrid_str;
+
+    fn tier(name: &str, spans: &[(f64, f64, &str)]) -> String {
+        let mut out = format!(
+            "    item [{{}}]:\n        class = \"IntervalTier\"\n        name = \"{}\"\n        xmin = 0\n        xmax = 1.5\n        intervals: size = {}\n",
+            name,
+            spans.len()
+        );
+        for (i, (xmin, xmax, text)) in spans.iter().enumerate() {
+            out.push_str(&format!(
+                "        intervals [{}]:\n            xmin = {}\n            xmax = {}\n            text = \"{}\"\n",
+                i + 1,
+                xmin,
+                xmax,
+                text
+            ));
+        }
+        out
+    }
+
+    /// Words `ya` and `n` between silences, with their phones.
+    fn fixture() -> TextGrid {
+        let words = tier("words", &[(0.0, 0.5, "SP"), (0.5, 1.0, "ya"), (1.0, 1.2, "n"), (1.2, 1.5, "SP")]);
+        let phones = tier("phones", &[(0.0, 0.5, "SP"), (0.5, 0.7, "y"), (0.7, 1.0, "a"), (1.0, 1.2, "n"), (1.2, 1.5, "SP")]);
+        let content = format!(
+            "File type = \"ooTextFile\"\nObject class = \"TextGrid\"\n\nxmin = 0\nxmax = 1.5\ntiers? <exists>\nsize = 2\nitem []:\n{}{}",
+            words.replacen("item [{}]", "item [1]", 1),
+            phones.replacen("item [{}]", "item [2]", 1)
+        );
+        parse_textgrid_str(&content).unwrap()
+    }
+
+    fn rule(terms: &[&str]) -> ReplaceRule {
+        ReplaceRule {
+            rule_name: "test".into(),
+            term_seq_length: terms[0].split_whitespace().count(),
+            search_terms: terms.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn marks(rule: &ReplaceRule) -> (Vec<Vec<usize>>, Vec<String>) {
+        let silence = vec!["SP".to_string(), "AP".to_string()];
+        let compiled = CompiledRule::compile(rule, &PhonemeClasses::new(), &silence).unwrap();
+        find_marks(&compiled, &fixture(), Some(0), 1)
+    }
+
+    #[test]
+    fn finds_marks_with_titles() {
+        let (idxs, titles) = marks(&rule(&["a"]));
+        assert_eq!(idxs, vec![vec![2]]);
+        assert_eq!(titles, vec!["(ya) SP y [a] n SP"]);
+    }
+
+    #[test]
+    fn word_position() {
+        let mut r = rule(&["{y,a}"]);
+        r.word_position = WordPosition::Start;
+        assert_eq!(marks(&r).0, vec![vec![1]]);
+        r.word_position = WordPosition::End;
+        assert_eq!(marks(&r).0, vec![vec![2]]);
+        r.word_position = WordPosition::Whole;
+        assert!(marks(&r).0.is_empty());
+    }
+
+    #[test]
+    fn span_mode() {
+        let mut r = rule(&["* *"]);
+        r.span_mode = SpanMode::WithinWord;
+        assert_eq!(marks(&r).0, vec![vec![1, 2]]);
+        r.span_mode = SpanMode::CrossBoundary;
+        assert_eq!(marks(&r).0, vec![vec![0, 1], vec![2, 3], vec![3, 4]]);
+    }
+
+    #[test]
+    fn contexts_and_word_filter() {
+        let mut r = rule(&["*"]);
+        r.left_contexts = vec!["y".into()];
+        assert_eq!(marks(&r).0, vec![vec![2]]);
+        let mut r = rule(&["{a,n}"]);
+        r.word_filter = vec!["n".into()];
+        assert_eq!(marks(&r).0, vec![vec![3]]);
+    }
+
+    #[test]
+    fn skip_silence_matches_across_it() {
+        let mut r = rule(&["n y"]);
+        assert!(marks(&r).0.is_empty());
+        r.search_terms = vec!["SP y".into()];
+        assert_eq!(marks(&r).0, vec![vec![0, 1]]);
+        r.skip_silence = true;
+        assert!(marks(&r).0.is_empty());
+    }
+
+    #[test]
+    fn resolves_tiers() {
+        let tg = fixture();
+        let names = |n: &[&str]| n.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(resolve_tiers(&tg, &names(&["words"]), &names(&["phones"])), Some((Some(0), 1)));
+        assert_eq!(resolve_tiers(&tg, &names(&["phones"]), &names(&["nothing"])), Some((Some(1), 0)));
+        assert_eq!(resolve_target_tier(&TargetTier::from_name("Words"), &tg, Some(0), 1), Some(0));
+        assert_eq!(resolve_target_tier(&TargetTier::from_name("phones"), &tg, Some(0), 1), Some(1));
+        assert_eq!(resolve_target_tier(&TargetTier::from_name("tones"), &tg, Some(0), 1), None);
+    }
+}
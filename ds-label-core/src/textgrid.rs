@@ -0,0 +1,879 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use pest::iterators::Pair;
+use pest::Parser;
+use pest_derive::Parser;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[grammar = "textgrid.pest"]
+struct TextGridParser;
+
+/// The two text layouts Praat can write a TextGrid in.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum TextGridFormat {
+    /// "text file", every value is labelled (`xmin = 0`).
+    #[default]
+    Long,
+    /// "short text file", one bare value per line.
+    Short,
+}
+
+/// The tier kinds a TextGrid can hold, named after their Praat classes.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum TierClass {
+    #[default]
+    IntervalTier,
+    TextTier,
+}
+
+impl TierClass {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TierClass::IntervalTier => "IntervalTier",
+            TierClass::TextTier => "TextTier",
+        }
+    }
+}
+
+/// A time in seconds that remembers how it was spelled in the source file.
+///
+/// As long as the value is not changed it is written back exactly as it was read,
+/// so saving does not reformat numbers that were never touched.
+#[derive(Clone, Default, Debug)]
+pub struct TextGridTime {
+    value: f64,
+    raw: Option<String>,
+}
+
+impl TextGridTime {
+    pub fn new(value: f64) -> Self {
+        TextGridTime { value, raw: None }
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    fn from_raw(raw: &str) -> Option<Self> {
+        raw.parse::<f64>().ok().map(|value| TextGridTime { value, raw: Some(raw.to_string()) })
+    }
+}
+
+impl From<f64> for TextGridTime {
+    fn from(value: f64) -> Self {
+        TextGridTime::new(value)
+    }
+}
+
+impl PartialEq for TextGridTime {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl fmt::Display for TextGridTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.raw {
+            Some(raw) if raw.parse::<f64>().ok() == Some(self.value) => f.write_str(raw),
+            _ => write!(f, "{}", self.value),
+        }
+    }
+}
+
+impl serde::Serialize for TextGridTime {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.value)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for TextGridTime {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        f64::deserialize(deserializer).map(TextGridTime::new)
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TextGridInterval {
+    pub xmin: TextGridTime,
+    pub xmax: TextGridTime,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TextGridPoint {
+    pub number: TextGridTime,
+    pub mark: String,
+}
+
+/// A single tier. Interval tiers only fill `intervals`, point tiers only fill `points`.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TextGridTier {
+    pub class: TierClass,
+    pub name: String,
+    pub xmin: TextGridTime,
+    pub xmax: TextGridTime,
+    pub intervals: Vec<TextGridInterval>,
+    pub points: Vec<TextGridPoint>,
+}
+
+/// Whitespace details of the source file that the grammar skips over,
+/// kept so that the writer can reproduce them.
+#[derive(Clone, Debug)]
+struct TextGridLayout {
+    newline: String,
+    /// Appended to value lines; Praat itself writes a single trailing space.
+    value_suffix: String,
+    blank_after_header: bool,
+    item_indent: String,
+    item_property_indent: String,
+    element_indent: String,
+    element_property_indent: String,
+    trailing_newline: bool,
+}
+
+impl Default for TextGridLayout {
+    fn default() -> Self {
+        TextGridLayout {
+            newline: "\n".to_string(),
+            value_suffix: String::new(),
+            blank_after_header: true,
+            item_indent: " ".repeat(4),
+            item_property_indent: " ".repeat(8),
+            element_indent: " ".repeat(12),
+            element_property_indent: " ".repeat(16),
+            trailing_newline: true,
+        }
+    }
+}
+
+impl TextGridLayout {
+    fn detect(content: &str, format: TextGridFormat) -> Self {
+        let mut layout = TextGridLayout::default();
+        if content.contains("\r\n") {
+            layout.newline = "\r\n".to_string();
+        }
+        layout.trailing_newline = content.ends_with('\n');
+
+        let lines: Vec<&str> = content.lines().collect();
+        layout.blank_after_header = lines.get(2).map_or(true, |line| line.trim().is_empty());
+
+        let indent_of = |line: &str| line[..line.len() - line.trim_start().len()].to_string();
+        let suffix_of = |line: &str| line[line.trim_end().len()..].to_string();
+
+        match format {
+            TextGridFormat::Long => {
+                if let Some(line) = lines.iter().find(|line| line.trim_start().starts_with("xmin =")) {
+                    layout.value_suffix = suffix_of(line);
+                }
+                if let Some(line) = lines.iter().find(|line| line.trim_start().starts_with("item [") && !line.contains("item []")) {
+                    layout.item_indent = indent_of(line);
+                }
+                if let Some(line) = lines.iter().find(|line| line.trim_start().starts_with("class =")) {
+                    layout.item_property_indent = indent_of(line);
+                }
+                let element = lines.iter().position(|line| {
+                    let line = line.trim_start();
+                    line.starts_with("intervals [") || line.starts_with("points [")
+                });
+                if let Some(pos) = element {
+                    layout.element_indent = indent_of(lines[pos]);
+                    if let Some(line) = lines.get(pos + 1) {
+                        layout.element_property_indent = indent_of(line);
+                    }
+                }
+            }
+            TextGridFormat::Short => {
+                let first_value = if layout.blank_after_header { 3 } else { 2 };
+                if let Some(line) = lines.get(first_value) {
+                    layout.value_suffix = suffix_of(line);
+                }
+            }
+        }
+        layout
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TextGrid {
+    pub format: TextGridFormat,
+    pub xmin: TextGridTime,
+    pub xmax: TextGridTime,
+    pub items: Vec<TextGridTier>,
+    #[serde(skip)]
+    layout: TextGridLayout,
+}
+
+impl TextGrid {
+    /// Indices of the interval tiers, in file order.
+    pub fn interval_tier_idxs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.class == TierClass::IntervalTier)
+            .map(|(i, _)| i)
+    }
+
+    /// Index of the first interval tier named like one of `names`, ignoring case.
+    pub fn find_interval_tier(&self, names: &[String]) -> Option<usize> {
+        names.iter().find_map(|name| {
+            self.interval_tier_idxs()
+                .find(|&i| self.items[i].name.trim().eq_ignore_ascii_case(name.trim()))
+        })
+    }
+}
+
+/// Turns the raw content of a quoted Praat string into the label it stands for.
+fn unescape_string(raw: &str) -> String {
+    raw.replace("\"\"", "\"")
+}
+
+/// Inverse of `unescape_string`, so the label can be put back between quotes.
+fn escape_string(label: &str) -> String {
+    label.replace('"', "\"\"")
+}
+
+/// Accumulates output lines using the layout of the file being written.
+struct TextGridWriter<'a> {
+    layout: &'a TextGridLayout,
+    result: String,
+}
+
+impl<'a> TextGridWriter<'a> {
+    fn new(layout: &'a TextGridLayout) -> Self {
+        let mut writer = TextGridWriter { layout, result: String::new() };
+        writer.line("", "File type = \"ooTextFile\"");
+        writer.line("", "Object class = \"TextGrid\"");
+        if layout.blank_after_header {
+            writer.line("", "");
+        }
+        writer
+    }
+
+    fn line(&mut self, indent: &str, text: &str) {
+        self.result.push_str(indent);
+        self.result.push_str(text);
+        self.result.push_str(&self.layout.newline);
+    }
+
+    fn value_line(&mut self, indent: &str, text: &str) {
+        self.result.push_str(indent);
+        self.result.push_str(text);
+        self.result.push_str(&self.layout.value_suffix);
+        self.result.push_str(&self.layout.newline);
+    }
+
+    fn finish(mut self) -> String {
+        if !self.layout.trailing_newline {
+            let len = self.result.trim_end_matches(['\r', '\n']).len();
+            self.result.truncate(len);
+        }
+        self.result
+    }
+}
+
+/// Serializes the TextGrid in the format and layout it was read from.
+pub fn textgrid_to_string(tg: &TextGrid) -> String {
+    match tg.format {
+        TextGridFormat::Long => textgrid_to_long_string(tg),
+        TextGridFormat::Short => textgrid_to_short_string(tg),
+    }
+}
+
+fn textgrid_to_long_string(tg: &TextGrid) -> String {
+    let layout = &tg.layout;
+    let mut w = TextGridWriter::new(layout);
+    w.value_line("", &format!("xmin = {}", tg.xmin));
+    w.value_line("", &format!("xmax = {}", tg.xmax));
+    w.value_line("", "tiers? <exists>");
+    w.value_line("", &format!("size = {}", tg.items.len()));
+    w.value_line("", "item []:");
+
+    for (i, item) in tg.items.iter().enumerate() {
+        let indent = &layout.item_property_indent;
+        w.line(&layout.item_indent, &format!("item [{}]:", i + 1));
+        w.value_line(indent, &format!("class = \"{}\"", item.class.as_str()));
+        w.value_line(indent, &format!("name = \"{}\"", escape_string(&item.name)));
+        w.value_line(indent, &format!("xmin = {}", item.xmin));
+        w.value_line(indent, &format!("xmax = {}", item.xmax));
+        let indent = &layout.element_property_indent;
+        match item.class {
+            TierClass::IntervalTier => {
+                w.value_line(&layout.item_property_indent, &format!("intervals: size = {}", item.intervals.len()));
+                for (j, interval) in item.intervals.iter().enumerate() {
+                    w.line(&layout.element_indent, &format!("intervals [{}]:", j + 1));
+                    w.value_line(indent, &format!("xmin = {}", interval.xmin));
+                    w.value_line(indent, &format!("xmax = {}", interval.xmax));
+                    w.value_line(indent, &format!("text = \"{}\"", escape_string(&interval.text)));
+                }
+            }
+            TierClass::TextTier => {
+                w.value_line(&layout.item_property_indent, &format!("points: size = {}", item.points.len()));
+                for (j, point) in item.points.iter().enumerate() {
+                    w.line(&layout.element_indent, &format!("points [{}]:", j + 1));
+                    w.value_line(indent, &format!("number = {}", point.number));
+                    w.value_line(indent, &format!("mark = \"{}\"", escape_string(&point.mark)));
+                }
+            }
+        }
+    }
+    w.finish()
+}
+
+fn textgrid_to_short_string(tg: &TextGrid) -> String {
+    let mut w = TextGridWriter::new(&tg.layout);
+    w.value_line("", &tg.xmin.to_string());
+    w.value_line("", &tg.xmax.to_string());
+    w.value_line("", "<exists>");
+    w.value_line("", &tg.items.len().to_string());
+
+    for item in tg.items.iter() {
+        w.value_line("", &format!("\"{}\"", item.class.as_str()));
+        w.value_line("", &format!("\"{}\"", escape_string(&item.name)));
+        w.value_line("", &item.xmin.to_string());
+        w.value_line("", &item.xmax.to_string());
+        match item.class {
+            TierClass::IntervalTier => {
+                w.value_line("", &item.intervals.len().to_string());
+                for interval in item.intervals.iter() {
+                    w.value_line("", &interval.xmin.to_string());
+                    w.value_line("", &interval.xmax.to_string());
+                    w.value_line("", &format!("\"{}\"", escape_string(&interval.text)));
+                }
+            }
+            TierClass::TextTier => {
+                w.value_line("", &item.points.len().to_string());
+                for point in item.points.iter() {
+                    w.value_line("", &point.number.to_string());
+                    w.value_line("", &format!("\"{}\"", escape_string(&point.mark)));
+                }
+            }
+        }
+    }
+    w.finish()
+}
+
+/// Why and where a TextGrid could not be read.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TextGridError {
+    pub file: Option<PathBuf>,
+    /// 1-based position of the problem, absent for I/O errors.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Grammar rules that would have been accepted at the position.
+    pub expected: Vec<String>,
+    /// The offending source line.
+    pub snippet: String,
+    pub message: String,
+}
+
+impl TextGridError {
+    fn message(message: String) -> Self {
+        TextGridError { message, ..Default::default() }
+    }
+
+    fn at(pair: &Pair<Rule>, message: String) -> Self {
+        let (line, column) = pair.as_span().start_pos().line_col();
+        TextGridError {
+            line: Some(line),
+            column: Some(column),
+            snippet: pair.as_span().start_pos().line_of().trim_end().to_string(),
+            message,
+            ..Default::default()
+        }
+    }
+
+    fn with_file(mut self, file: PathBuf) -> Self {
+        self.file = Some(file);
+        self
+    }
+}
+
+impl From<pest::error::Error<Rule>> for TextGridError {
+    fn from(e: pest::error::Error<Rule>) -> Self {
+        let (line, column) = match e.line_col {
+            pest::error::LineColLocation::Pos(pos) => pos,
+            pest::error::LineColLocation::Span(start, _) => start,
+        };
+        let expected = match &e.variant {
+            pest::error::ErrorVariant::ParsingError { positives, .. } => {
+                positives.iter().map(|rule| format!("{:?}", rule)).collect()
+            }
+            pest::error::ErrorVariant::CustomError { .. } => Vec::new(),
+        };
+        TextGridError {
+            file: None,
+            line: Some(line),
+            column: Some(column),
+            expected,
+            snippet: e.line().trim_end().to_string(),
+            message: e.variant.message().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for TextGridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}", file.display())?;
+            if let (Some(line), Some(column)) = (self.line, self.column) {
+                write!(f, ":{}:{}", line, column)?;
+            }
+            write!(f, ": ")?;
+        } else if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for TextGridError {}
+
+/// The encoding a TextGrid file was read in, so it can be written back the same way.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SourceEncoding {
+    pub name: String,
+    pub bom: bool,
+}
+
+impl Default for SourceEncoding {
+    fn default() -> Self {
+        SourceEncoding { name: UTF_8.name().to_string(), bom: false }
+    }
+}
+
+pub fn parse_textgrid(tg_file: &Path) -> Result<(TextGrid, SourceEncoding), Box<TextGridError>> {
+    let io_error = |e: std::io::Error| TextGridError::message(e.to_string()).with_file(tg_file.to_path_buf());
+    let file = File::open(tg_file).map_err(io_error)?;
+    let mut reader = BufReader::new(file);
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).map_err(io_error)?;
+
+    let bom = Encoding::for_bom(&buffer).is_some();
+    let encoding = if buffer.is_ascii() {
+        // Plain ASCII is valid in almost everything, remember it as UTF-8 so
+        // that non-ASCII replacements can still be written back.
+        UTF_8
+    } else {
+        let mut detector = EncodingDetector::new();
+        detector.feed(&buffer, true);
+        detector.guess(None, true)
+    };
+
+    // `decode` sniffs and strips a BOM, which overrides the guess.
+    let (decoded, used_encoding, _) = encoding.decode(&buffer);
+    let tg = parse_textgrid_str(&decoded).map_err(|e| Box::new(e.with_file(tg_file.to_path_buf())))?;
+    Ok((tg, SourceEncoding { name: used_encoding.name().to_string(), bom }))
+}
+
+/// Content that cannot be written in the encoding it was read in. Callers can offer
+/// writing UTF-8 instead.
+#[derive(Debug, thiserror::Error)]
+#[error("The content cannot be represented in {encoding}.")]
+pub struct EncodeError {
+    pub encoding: String,
+}
+
+/// Encodes serialized TextGrid content for writing to disk.
+pub fn encode_textgrid(content: &str, source: &SourceEncoding) -> Result<Vec<u8>, EncodeError> {
+    let encoding = Encoding::for_label(source.name.as_bytes()).unwrap_or(UTF_8);
+    // encoding_rs never encodes into UTF-16, it has to be done by hand.
+    let mut bytes = Vec::new();
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        let little_endian = encoding == UTF_16LE;
+        if source.bom {
+            bytes.extend_from_slice(if little_endian { &[0xFF, 0xFE] } else { &[0xFE, 0xFF] });
+        }
+        for unit in content.encode_utf16() {
+            bytes.extend_from_slice(&if little_endian { unit.to_le_bytes() } else { unit.to_be_bytes() });
+        }
+        return Ok(bytes);
+    }
+    if encoding == UTF_8 && source.bom {
+        bytes.extend_from_slice(&[0xEF, 0xBB, 0xBF]);
+    }
+    let (encoded, _, unmappable) = encoding.encode(content);
+    if unmappable {
+        return Err(EncodeError { encoding: encoding.name().to_string() });
+    }
+    bytes.extend_from_slice(&encoded);
+    Ok(bytes)
+}
+
+/// Serializes a TextGrid and writes it to `path` in the given encoding.
+pub fn write_textgrid(path: &Path, tg: &TextGrid, encoding: &SourceEncoding) -> Result<(), crate::Error> {
+    let bytes = encode_textgrid(&textgrid_to_string(tg), encoding)?;
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+/// Parses decoded TextGrid content, accepting both the long and the short format.
+pub fn parse_textgrid_str(content: &str) -> Result<TextGrid, Box<TextGridError>> {
+    let parsed = TextGridParser::parse(Rule::file, content).map_err(TextGridError::from)?;
+
+    let mut tg = TextGrid::default();
+
+    for record in parsed {
+        if record.as_rule() != Rule::file { continue; }
+        for body in record.into_inner() {
+            match body.as_rule() {
+                Rule::long_body => {
+                    tg.format = TextGridFormat::Long;
+                    parse_long_body(body, &mut tg)?;
+                }
+                Rule::short_body => {
+                    tg.format = TextGridFormat::Short;
+                    parse_short_body(body, &mut tg)?;
+                }
+                _ => {}
+            }
+        }
+    }
+    tg.layout = TextGridLayout::detect(content, tg.format);
+    Ok(tg)
+}
+
+fn parse_time(pair: &Pair<Rule>) -> Result<TextGridTime, Box<TextGridError>> {
+    TextGridTime::from_raw(pair.as_str())
+        .ok_or_else(|| Box::new(TextGridError::at(pair, format!("invalid number \"{}\"", pair.as_str()))))
+}
+
+fn parse_tier_class(pair: Pair<Rule>) -> TierClass {
+    match pair.as_str() {
+        "TextTier" => TierClass::TextTier,
+        _ => TierClass::IntervalTier,
+    }
+}
+
+fn parse_long_body(body: Pair<Rule>, tg: &mut TextGrid) -> Result<(), Box<TextGridError>> {
+    for content in body.into_inner() {
+        match content.as_rule() {
+            Rule::property => {
+                let mut inner_rules = content.into_inner();
+                let name: &str = inner_rules.next().unwrap().as_str();
+                let value = inner_rules.next().unwrap();
+                match name {
+                    "xmin" => tg.xmin = parse_time(&value)?,
+                    "xmax" => tg.xmax = parse_time(&value)?,
+                    _ => {}
+                }
+            }
+            Rule::items => {
+                for sub_content in content.into_inner() {
+                    if sub_content.as_rule() == Rule::single_item {
+                        tg.items.push(parse_long_item(sub_content)?);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_long_item(item: Pair<Rule>) -> Result<TextGridTier, Box<TextGridError>> {
+    let mut tg_item = TextGridTier::default();
+    for sub_item in item.into_inner() {
+        match sub_item.as_rule() {
+            Rule::tier_class => tg_item.class = parse_tier_class(sub_item),
+            Rule::property => {
+                let mut inner_rules = sub_item.into_inner();
+                let name: &str = inner_rules.next().unwrap().as_str();
+                let value = inner_rules.next().unwrap();
+                match name {
+                    "name" => tg_item.name = unescape_string(value.as_str()),
+                    "xmin" => tg_item.xmin = parse_time(&value)?,
+                    "xmax" => tg_item.xmax = parse_time(&value)?,
+                    _ => {}
+                }
+            }
+            Rule::single_interval => {
+                let mut tg_interval = TextGridInterval::default();
+                for interval in sub_item.into_inner() {
+                    if interval.as_rule() == Rule::property {
+                        let mut inner_rules = interval.into_inner();
+                        let name: &str = inner_rules.next().unwrap().as_str();
+                        let value = inner_rules.next().unwrap();
+                        match name {
+                            "xmin" => tg_interval.xmin = parse_time(&value)?,
+                            "xmax" => tg_interval.xmax = parse_time(&value)?,
+                            "text" => tg_interval.text = unescape_string(value.as_str()),
+                            _ => {}
+                        }
+                    }
+                }
+                tg_item.intervals.push(tg_interval);
+            }
+            Rule::single_point => {
+                let mut tg_point = TextGridPoint::default();
+                for point in sub_item.into_inner() {
+                    if point.as_rule() == Rule::property {
+                        let mut inner_rules = point.into_inner();
+                        let name: &str = inner_rules.next().unwrap().as_str();
+                        let value = inner_rules.next().unwrap();
+                        match name {
+                            "number" => tg_point.number = parse_time(&value)?,
+                            "mark" => tg_point.mark = unescape_string(value.as_str()),
+                            _ => {}
+                        }
+                    }
+                }
+                tg_item.points.push(tg_point);
+            }
+            _ => {}
+        }
+    }
+    Ok(tg_item)
+}
+
+fn parse_short_body(body: Pair<Rule>, tg: &mut TextGrid) -> Result<(), Box<TextGridError>> {
+    let mut header = Vec::new();
+    for content in body.into_inner() {
+        match content.as_rule() {
+            Rule::floating | Rule::integer => header.push(content),
+            Rule::short_item => tg.items.push(parse_short_item(content)?),
+            _ => {}
+        }
+    }
+    // The bare numbers are the file's xmin, xmax and tier count, in that order.
+    if let [xmin, xmax, _] = &header[..] {
+        tg.xmin = parse_time(xmin)?;
+        tg.xmax = parse_time(xmax)?;
+    }
+    Ok(())
+}
+
+fn parse_short_item(item: Pair<Rule>) -> Result<TextGridTier, Box<TextGridError>> {
+    let mut tg_item = TextGridTier::default();
+    let mut bounds = Vec::new();
+    for sub_item in item.into_inner() {
+        match sub_item.as_rule() {
+            Rule::tier_class => tg_item.class = parse_tier_class(sub_item),
+            Rule::string => tg_item.name = unescape_string(sub_item.as_str()),
+            Rule::floating | Rule::integer => bounds.push(sub_item),
+            Rule::short_interval => {
+                let mut inner_rules = sub_item.into_inner();
+                let xmin = inner_rules.next().unwrap();
+                let xmax = inner_rules.next().unwrap();
+                let text = inner_rules.next().unwrap();
+                tg_item.intervals.push(TextGridInterval {
+                    xmin: parse_time(&xmin)?,
+                    xmax: parse_time(&xmax)?,
+                    text: unescape_string(text.as_str()),
+                });
+            }
+            Rule::short_point => {
+                let mut inner_rules = sub_item.into_inner();
+                let number = inner_rules.next().unwrap();
+                let mark = inner_rules.next().unwrap();
+                tg_item.points.push(TextGridPoint {
+                    number: parse_time(&number)?,
+                    mark: unescape_string(mark.as_str()),
+                });
+            }
+            _ => {}
+        }
+    }
+    // The bare numbers are the tier's xmin, xmax and element count, in that order.
+    if let [xmin, xmax, _] = &bounds[..] {
+        tg_item.xmin = parse_time(xmin)?;
+        tg_item.xmax = parse_time(xmax)?;
+    }
+    Ok(tg_item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// As Praat writes it, values followed by a space.
+    const LONG: &str = "File type = \"ooTextFile\"
+Object class = \"TextGrid\"
+
+xmin = 0 
+xmax = 1.5 
+tiers? <exists> 
+size = 2 
+item []: 
+    item [1]:
+        class = \"IntervalTier\" 
+        name = \"phones\" 
+        xmin = 0 
+        xmax = 1.5 
+        intervals: size = 2 
+        intervals [1]:
+            xmin = 0 
+            xmax = 0.50 
+            text = \"SP\" 
+        intervals [2]:
+            xmin = 0.50 
+            xmax = 1.5 
+            text = \"a\" 
+    item [2]:
+        class = \"TextTier\" 
+        name = \"tones\" 
+        xmin = 0 
+        xmax = 1.5 
+        points: size = 1 
+        points [1]:
+            number = 0.75 
+            mark = \"H\" 
+";
+
+    const SHORT: &str = "File type = \"ooTextFile\"
+Object class = \"TextGrid\"
+
+0
+1.5
+<exists>
+2
+\"IntervalTier\"
+\"phones\"
+0
+1.5
+2
+0
+0.50
+\"SP\"
+0.50
+1.5
+\"a\"
+\"TextTier\"
+\"tones\"
+0
+1.5
+1
+0.75
+\"H\"
+";
+
+    #[test]
+    fn parses_long_format() {
+        let tg = parse_textgrid_str(LONG).unwrap();
+        assert_eq!(tg.format, TextGridFormat::Long);
+        assert_eq!(tg.xmax.value(), 1.5);
+        assert_eq!(tg.items.len(), 2);
+        let phones = &tg.items[0];
+        assert_eq!((phones.class, phones.name.as_str()), (TierClass::IntervalTier, "phones"));
+        assert_eq!(phones.intervals.iter().map(|i| i.text.as_str()).collect::<Vec<_>>(), ["SP", "a"]);
+        assert_eq!(phones.intervals[1].xmin.value(), 0.5);
+        let tones = &tg.items[1];
+        assert_eq!(tones.class, TierClass::TextTier);
+        assert_eq!((tones.points[0].number.value(), tones.points[0].mark.as_str()), (0.75, "H"));
+    }
+
+    #[test]
+    fn short_format_reads_the_same() {
+        let long = parse_textgrid_str(LONG).unwrap();
+        let short = parse_textgrid_str(SHORT).unwrap();
+        assert_eq!(short.format, TextGridFormat::Short);
+        assert_eq!(format!("{:?}", long.items), format!("{:?}", short.items));
+        assert_eq!((short.xmin.value(), short.xmax.value()), (0.0, 1.5));
+    }
+
+    #[test]
+    fn finds_interval_tiers_by_name() {
+        let tg = parse_textgrid_str(LONG).unwrap();
+        assert_eq!(tg.interval_tier_idxs().collect::<Vec<_>>(), [0]);
+        assert_eq!(tg.find_interval_tier(&["Phones".to_string()]), Some(0));
+        assert_eq!(tg.find_interval_tier(&["tones".to_string()]), None);
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        let broken = LONG.replace("xmax = 0.50 ", "xmax = x ");
+        let error = parse_textgrid_str(&broken).unwrap_err();
+        assert_eq!(error.line, Some(17));
+        assert!(error.snippet.contains("xmax = x"));
+        assert!(error.to_string().starts_with("17:"));
+    }
+
+    #[test]
+    fn edited_times_are_written_as_numbers() {
+        let mut tg = parse_textgrid_str(LONG).unwrap();
+        tg.items[0].intervals[0].xmax = TextGridTime::new(0.25);
+        tg.items[0].intervals[1].xmin = TextGridTime::new(0.25);
+        let written = textgrid_to_string(&tg);
+        assert!(written.contains("            xmax = 0.25 \n"));
+        assert!(!written.contains("0.50"));
+    }
+
+    fn assert_round_trip(content: &str) {
+        let tg = parse_textgrid_str(content).unwrap();
+        assert_eq!(textgrid_to_string(&tg), content);
+    }
+
+    #[test]
+    fn untouched_long_file_is_written_back_identically() {
+        assert_round_trip(LONG);
+    }
+
+    #[test]
+    fn keeps_crlf_newlines() {
+        assert_round_trip(&LONG.replace('\n', "\r\n"));
+    }
+
+    #[test]
+    fn keeps_a_missing_trailing_newline_and_other_indentation() {
+        let content = LONG.trim_end().replace("\n    ", "\n  ").replace(" \n", "\n");
+        assert_round_trip(&content);
+    }
+
+    #[test]
+    fn keeps_multi_line_labels() {
+        let content = LONG.replace("text = \"a\"", "text = \"a\nb\"");
+        let tg = parse_textgrid_str(&content).unwrap();
+        assert_eq!(tg.items[0].intervals[1].text, "a\nb");
+        assert_eq!(textgrid_to_string(&tg), content);
+    }
+
+    #[test]
+    fn untouched_short_file_is_written_back_identically() {
+        assert_round_trip(SHORT);
+        assert_round_trip(&SHORT.replace('\n', "\r\n"));
+        let (header, values) = SHORT.split_at(SHORT.find("\n\n").unwrap() + 2);
+        assert_round_trip(&format!("{}{}", header, values.replace('\n', " \n")));
+    }
+
+    #[test]
+    fn edited_short_file_stays_short() {
+        let mut tg = parse_textgrid_str(SHORT).unwrap();
+        tg.items[0].intervals[1].text = "i".into();
+        assert_eq!(textgrid_to_string(&tg), SHORT.replace("\"a\"", "\"i\""));
+    }
+
+    #[test]
+    fn keeps_escaped_quotes() {
+        let content = LONG.replace("text = \"a\"", "text = \"say \"\"a\"\" \\n\"");
+        let tg = parse_textgrid_str(&content).unwrap();
+        assert_eq!(tg.items[0].intervals[1].text, "say \"a\" \\n");
+        assert_eq!(textgrid_to_string(&tg), content);
+
+        let short = SHORT.replace("\"SP\"", "\"\"\"\"");
+        let tg = parse_textgrid_str(&short).unwrap();
+        assert_eq!(tg.items[0].intervals[0].text, "\"");
+        assert_eq!(textgrid_to_string(&tg), short);
+    }
+
+    #[test]
+    fn escapes_edited_labels() {
+        let mut tg = parse_textgrid_str(LONG).unwrap();
+        tg.items[0].intervals[1].text = "\"b\"".into();
+        assert!(textgrid_to_string(&tg).contains("text = \"\"\"b\"\"\" \n"));
+        assert_eq!(parse_textgrid_str(&textgrid_to_string(&tg)).unwrap().items[0].intervals[1].text, "\"b\"");
+    }
+
+    #[test]
+    fn encodes_in_the_source_encoding() {
+        let gbk = SourceEncoding { name: "GBK".into(), bom: false };
+        assert_eq!(encode_textgrid("啊", &gbk).unwrap(), [0xB0, 0xA1]);
+        assert!(encode_textgrid("ア\u{1F600}", &gbk).is_err());
+        let utf16 = SourceEncoding { name: "UTF-16LE".into(), bom: true };
+        assert_eq!(encode_textgrid("a", &utf16).unwrap(), [0xFF, 0xFE, b'a', 0]);
+        let utf8 = SourceEncoding { name: "UTF-8".into(), bom: true };
+        assert_eq!(encode_textgrid("a", &utf8).unwrap(), [0xEF, 0xBB, 0xBF, b'a']);
+    }
+}
@@ -0,0 +1,197 @@
+//! Batch find and replace without the GUI, for use on headless machines.
+
+use clap::Parser;
+use ds_label_core::{
+    apply_selected_options, encode_textgrid, find_textgrids, parse_textgrid, read_project_file, scan_textgrid, textgrid_to_string, write_item,
+    Error, SourceEncoding,
+};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Exit code when some files could not be read or written.
+const EXIT_FILE_ERRORS: u8 = 1;
+/// Exit code for unusable arguments or project files, as clap uses for bad arguments.
+const EXIT_USAGE: u8 = 2;
+/// Added to errors of files that cannot be written in their own encoding.
+const FORCE_UTF8_HINT: &str = "Pass --force-utf8 to write UTF-8.";
+
+#[derive(Parser)]
+#[command(version, about = "Apply the rules of a project to a TextGrid folder without the GUI")]
+struct Args {
+    /// Project file written by the app's Save project.
+    #[arg(long)]
+    project: PathBuf,
+    /// TextGrid folder, defaults to the one saved in the project.
+    #[arg(long)]
+    textgrids: Option<PathBuf>,
+    /// Option every mark of a rule is replaced with, as RULE=OPTION where OPTION is the
+    /// option text or its number counting from 1. Only rules given here are applied.
+    #[arg(long = "option", value_name = "RULE=OPTION", required = true)]
+    options: Vec<String>,
+    /// Only take TextGrids whose path relative to the folder matches one of these globs,
+    /// replacing the project's include globs.
+    #[arg(long = "include", value_name = "GLOB")]
+    include_patterns: Vec<String>,
+    /// Leave out TextGrids whose relative path matches one of these globs, replacing the
+    /// project's exclude globs.
+    #[arg(long = "exclude", value_name = "GLOB")]
+    exclude_patterns: Vec<String>,
+    /// Also scan subfolders, whatever the project says.
+    #[arg(long, overrides_with = "no_recursive")]
+    recursive: bool,
+    /// Only scan the folder itself, whatever the project says.
+    #[arg(long, overrides_with = "recursive")]
+    no_recursive: bool,
+    /// Report what would change without writing any file.
+    #[arg(long)]
+    dry_run: bool,
+    /// Keep a .TextGrid.bak copy of every file before overwriting it.
+    #[arg(long)]
+    backup: bool,
+    /// Write UTF-8 instead of each file's own encoding.
+    #[arg(long)]
+    force_utf8: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match replace_all(&args) {
+        Ok(summary) => {
+            summary.print(args.dry_run);
+            if summary.failures.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(EXIT_FILE_ERRORS)
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(EXIT_USAGE)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    files_scanned: usize,
+    files_changed: Vec<(PathBuf, usize)>,
+    marks_per_rule: BTreeMap<String, usize>,
+    failures: Vec<String>,
+}
+
+impl Summary {
+    fn print(&self, dry_run: bool) {
+        for (path, marks) in self.files_changed.iter() {
+            println!("{}: {} marks", path.display(), marks);
+        }
+        for failure in self.failures.iter() {
+            eprintln!("failed: {}", failure);
+        }
+        println!(
+            "{} files scanned, {} files {}, {} failed",
+            self.files_scanned,
+            self.files_changed.len(),
+            if dry_run { "would be changed" } else { "changed" },
+            self.failures.len()
+        );
+        for (rule, marks) in self.marks_per_rule.iter() {
+            println!("  {}: {} marks", rule, marks);
+        }
+    }
+}
+
+fn replace_all(args: &Args) -> Result<Summary, String> {
+    let (mut proj_state, _) = read_project_file(&args.project).map_err(|e| e.to_string())?;
+    let tg_folder = args
+        .textgrids
+        .clone()
+        .or_else(|| proj_state.tg_folder.clone())
+        .ok_or_else(|| "No TextGrid folder given and none saved in the project".to_string())?;
+
+    let mut default_options: BTreeMap<String, i32> = BTreeMap::new();
+    let mut rule_idxs = Vec::new();
+    for arg in args.options.iter() {
+        let (rule_name, option) = arg
+            .split_once('=')
+            .ok_or_else(|| format!("Option must be written as RULE=OPTION: {}", arg))?;
+        let rule_idx = proj_state
+            .rules
+            .iter()
+            .position(|r| r.rule_name == rule_name)
+            .ok_or_else(|| format!("No rule named {} in the project", rule_name))?;
+        let replace_options = &proj_state.rules[rule_idx].replace_options;
+        let opt_idx = match option.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= replace_options.len() => n - 1,
+            _ => replace_options
+                .iter()
+                .position(|o| o == option)
+                .ok_or_else(|| format!("Rule {} has no option {}", rule_name, option))?,
+        };
+        default_options.insert(rule_name.to_string(), opt_idx as i32);
+        rule_idxs.push(rule_idx);
+    }
+    proj_state.active_rule_idxs = rule_idxs;
+
+    if !args.include_patterns.is_empty() {
+        proj_state.include_patterns = args.include_patterns.clone();
+    }
+    if !args.exclude_patterns.is_empty() {
+        proj_state.exclude_patterns = args.exclude_patterns.clone();
+    }
+    if args.recursive || args.no_recursive {
+        proj_state.recursive_scan = args.recursive;
+    }
+    let filter = proj_state.textgrid_filter().map_err(|e| e.to_string())?;
+    let compiled_rules = proj_state.compile_session_rules().map_err(|e| e.to_string())?;
+    let mut summary = Summary::default();
+    let mut paths = match find_textgrids(&tg_folder, &filter) {
+        Ok(paths) => paths,
+        Err(e) => {
+            // An unreadable folder is a read error like an unreadable file.
+            summary.failures.push(e.to_string());
+            return Ok(summary);
+        }
+    };
+    paths.sort_by(|a, b| natord::compare(&a.to_string_lossy(), &b.to_string_lossy()));
+    for path in paths {
+        summary.files_scanned += 1;
+        let (tg, tg_encoding) = match parse_textgrid(&path) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                summary.failures.push(e.to_string());
+                continue;
+            }
+        };
+        let Some(mut item) = scan_textgrid(&proj_state, &compiled_rules, &tg_folder, &path, tg, tg_encoding) else {
+            continue;
+        };
+        item.selected_options = item.found_mark_rules.iter().map(|r| default_options.get(r).copied()).collect();
+        let result = apply_selected_options(&item).map_err(|e| e.to_string()).and_then(|new_tg| {
+            let content = textgrid_to_string(&new_tg);
+            if content == textgrid_to_string(&item.tg_content) {
+                Ok(false)
+            } else if args.dry_run {
+                // Catch what writing would fail on without touching the file.
+                let encoding = if args.force_utf8 { SourceEncoding::default() } else { item.tg_encoding.clone() };
+                encode_textgrid(&content, &encoding).map(|_| true).map_err(|e| format!("{} {}", e, FORCE_UTF8_HINT))
+            } else {
+                write_item(&item, args.backup, args.force_utf8).map(|_| true).map_err(|e| match e {
+                    Error::Encode(_) => format!("{} {}", e, FORCE_UTF8_HINT),
+                    _ => e.to_string(),
+                })
+            }
+        });
+        match result {
+            Ok(false) => {}
+            Ok(true) => {
+                for rule in item.found_mark_rules.iter() {
+                    *summary.marks_per_rule.entry(rule.clone()).or_default() += 1;
+                }
+                summary.files_changed.push((item.tg_rel_path.clone(), item.found_mark_idxs.len()));
+            }
+            Err(e) => summary.failures.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+    Ok(summary)
+}
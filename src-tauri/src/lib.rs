@@ -1,112 +1,39 @@
-use chardetng::EncodingDetector;
-use pest::Parser;
-use pest_derive::Parser;
 use rodio::cpal;
 use rodio::cpal::traits::{DeviceTrait, HostTrait};
 use rodio::source::SineWave;
 use rodio::{Decoder, OutputStream, Sample, Sink, Source};
-use std::cmp::{max, min};
-use std::collections::HashSet;
+use std::cmp::max;
+use std::collections::{HashSet, VecDeque};
 use std::default::Default;
-use std::fs::File;
-use std::io::{BufReader, Read};
-use std::path::PathBuf;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use std::{fs, io};
 use tauri::Wry;
+use rayon::prelude::*;
 use tauri::{Manager, State};
 // use tauri_plugin_fs;
 use tauri_plugin_store::{with_store, Store, StoreBuilder, StoreCollection};
 
+use ds_label_core::pattern::{ContextPattern, TermPattern, TokenPattern};
+use ds_label_core::{
+    drop_stale_decisions, find_textgrids, merge_decisions, parse_class_definition, parse_replace_option, parse_textgrid, read_project_file,
+    restore_decisions, scan_textgrid, write_item, write_project_file, Decisions, ItemOrder, ItemRecord, LostReason, MarkDecision, ProjectState,
+    ReplaceRule, SpanMode, TargetTier, TextGridError, TextGridFilter, WordPosition,
+};
+
 #[derive(Clone, serde::Serialize)]
 struct Payload {
     args: Vec<String>,
     cwd: String,
 }
 
-#[derive(Parser)]
-#[grammar = "textgrid.pest"]
-struct TextGridParser;
-
-#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
-struct TextGridInterval {
-    xmin: f32,
-    xmax: f32,
-    text: String,
-}
-
-#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
-struct TextGridIntervals {
-    name: String,
-    intervals: Vec<TextGridInterval>,
-}
-
-#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
-struct TextGrid {
-    items: Vec<TextGridIntervals>,
-}
-
-fn textgrid_to_string(tg: &TextGrid) -> String {
-    let mut result = String::new();
-    result.push_str("File type = \"ooTextFile\"\n");
-    result.push_str("Object class = \"TextGrid\"\n");
-    result.push_str("\n");
-    result.push_str("xmin = 0\n");
-    result.push_str(&format!(
-        "xmax = {}\n",
-        tg.items
-            .iter()
-            .map(|item| item
-                .intervals
-                .iter()
-                .map(|interval| interval.xmax)
-                .fold(0.0 / 0.0, f32::max))
-            .fold(0.0 / 0.0, f32::max)
-    ));
-    result.push_str("tiers? <exists>\n");
-    result.push_str(&format!("size = {}\n", tg.items.len()));
-    result.push_str("item []:\n");
-
-    for (i, item) in tg.items.iter().enumerate() {
-        result.push_str(&format!("    item [{}]:\n", i + 1));
-        result.push_str(&format!("        class = \"IntervalTier\"\n"));
-        result.push_str(&format!("        name = \"{}\"\n", item.name));
-        result.push_str(&format!("        xmin = 0\n"));
-        result.push_str(&format!("        xmax = {}\n", item.intervals.iter().map(|interval| interval.xmax).fold(0.0/0.0, f32::max)));
-        result.push_str(&format!("        intervals: size = {}\n", item.intervals.len()));
-
-        for (j, interval) in item.intervals.iter().enumerate() {
-            result.push_str(&format!("            intervals [{}]:\n", j + 1));
-            result.push_str(&format!("                xmin = {}\n", interval.xmin));
-            result.push_str(&format!("                xmax = {}\n", interval.xmax));
-            result.push_str(&format!("                text = \"{}\"\n", interval.text));
-        }
-    }
-    result
-}
-
-#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
-struct ReplaceRule {
-    rule_name: String,
-    term_seq_length: usize,
-    search_terms: Vec<String>,
-    replace_options: Vec<String>,
-}
-
-#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
-struct AppProjectState {
-    tg_folder: Option<PathBuf>,
-    wav_folder: Option<PathBuf>,
-    rules: Vec<ReplaceRule>,
-    selected_rule_idx: Option<i32>,
-    selected_term_idx: Option<i32>,
-    selected_opt_idx: Option<i32>,
-}
-
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 struct AppSettings {
     theme: String,
@@ -116,6 +43,8 @@ struct AppSettings {
     auto_next: bool,
     auto_play: bool,
     auto_scroll: bool,
+    force_utf8: bool,
+    skip_broken_files: bool,
 }
 
 impl AppSettings {
@@ -159,6 +88,16 @@ impl AppSettings {
             .and_then(|v| v.as_bool())
             .unwrap_or(true);
 
+        let force_utf8 = store
+            .get("appSettings.force_utf8")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        let skip_broken_files = store
+            .get("appSettings.skip_broken_files")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+
         Ok(AppSettings {
             theme,
             sound_device,
@@ -167,6 +106,8 @@ impl AppSettings {
             auto_next,
             auto_play,
             auto_scroll,
+            force_utf8,
+            skip_broken_files,
         })
     }
 
@@ -203,31 +144,333 @@ impl AppSettings {
             "appSettings.auto_scroll".to_string(),
             json.get("auto_scroll").unwrap().clone(),
         )?;
+        store.insert(
+            "appSettings.force_utf8".to_string(),
+            json.get("force_utf8").unwrap().clone(),
+        )?;
+        store.insert(
+            "appSettings.skip_broken_files".to_string(),
+            json.get("skip_broken_files").unwrap().clone(),
+        )?;
         store.save()?;
         Ok(())
     }
 }
 
-#[derive(Clone, serde::Serialize, serde::Deserialize)]
-struct ItemRecord {
-    tg_file: PathBuf,
-    tg_stem: String,
-    tg_content: TextGrid,
-    wav_file: Option<PathBuf>,
-    found_mark_idxs: Vec<usize>,
-    found_mark_titles: Vec<String>,
-    replace_options: Vec<String>,
-    term_seq_length: usize,
-    selected_options: Vec<Option<i32>>,
-    original_options: Vec<Option<i32>>,
-    dirty: bool,
-}
-
 #[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
 struct SessionItems {
     items: Vec<ItemRecord>,
     selected_item: Option<i32>,
     selected_mark: Vec<Option<i32>>,
+    problems: Vec<TextGridError>,
+    /// Decisions loaded with the project that no scanned mark has picked up yet.
+    pending_decisions: Vec<MarkDecision>,
+    filter: ItemFilter,
+}
+
+/// Narrows the marks the session shows and navigates through. Every condition that is
+/// set must hold, an item is shown when any of its marks is.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+struct ItemFilter {
+    /// Only marks neither replaced nor kept yet.
+    undecided: bool,
+    /// Only items with unsaved changes.
+    dirty: bool,
+    /// Only marks whose title contains this text, ignoring case.
+    title: String,
+    /// Only items under this folder, relative to the TextGrid folder.
+    folder: String,
+    /// Only marks where this option was chosen.
+    option: String,
+}
+
+impl ItemFilter {
+    fn is_empty(&self) -> bool {
+        !self.undecided && !self.dirty && self.title.is_empty() && self.folder.is_empty() && self.option.is_empty()
+    }
+
+    fn mark_matches(&self, item: &ItemRecord, mark_idx: usize) -> bool {
+        let chosen = item.selected_options[mark_idx].and_then(|o| item.replace_options[mark_idx].get(o as usize));
+        (!self.dirty || item.dirty)
+            && (self.folder.is_empty() || item.tg_rel_path.starts_with(self.folder.trim_matches(['/', '\\'])))
+            && (!self.undecided || !item.decided[mark_idx])
+            && (self.title.is_empty() || item.found_mark_titles[mark_idx].to_lowercase().contains(&self.title.to_lowercase()))
+            && (self.option.is_empty() || chosen == Some(&self.option))
+    }
+
+    fn item_matches(&self, item: &ItemRecord) -> bool {
+        (0..item.found_mark_idxs.len()).any(|m| self.mark_matches(item, m))
+    }
+}
+
+/// The items and, for each of them, the marks that pass the session's filter.
+#[derive(Clone, serde::Serialize)]
+struct FilteredView {
+    item_idxs: Vec<usize>,
+    mark_idxs: Vec<Vec<usize>>,
+}
+
+impl SessionItems {
+    /// All unsaved decisions of the session, merged with the pending ones.
+    fn collect_decisions(&self) -> Decisions {
+        merge_decisions(&self.items, &self.pending_decisions)
+    }
+
+    /// None when no filter is set, so that every item is shown.
+    fn filtered_view(&self) -> Option<FilteredView> {
+        if self.filter.is_empty() {
+            return None;
+        }
+        let mut view = FilteredView { item_idxs: Vec::new(), mark_idxs: Vec::new() };
+        for (i, item) in self.items.iter().enumerate() {
+            let marks: Vec<usize> = (0..item.found_mark_idxs.len()).filter(|&m| self.filter.mark_matches(item, m)).collect();
+            if !marks.is_empty() {
+                view.item_idxs.push(i);
+                view.mark_idxs.push(marks);
+            }
+        }
+        Some(view)
+    }
+
+    /// The selected mark of an item if the filter lets it through, otherwise the first one it does.
+    fn visible_mark(&self, item_idx: usize) -> Option<i32> {
+        let item = &self.items[item_idx];
+        match self.selected_mark[item_idx] {
+            Some(m) if self.filter.mark_matches(item, m as usize) => Some(m),
+            _ => (0..item.found_mark_idxs.len()).find(|&m| self.filter.mark_matches(item, m)).map(|m| m as i32),
+        }
+    }
+
+    /// The next item after the selected one the filter lets through, or the previous one
+    /// when not `forward`.
+    fn step_item(&self, forward: bool) -> Option<usize> {
+        let current = self.selected_item.map(|i| i as i64);
+        let mut idxs = 0..self.items.len();
+        let is_candidate = |&i: &usize| self.filter.item_matches(&self.items[i]);
+        if forward {
+            idxs.find(|i| current.map_or(true, |c| *i as i64 > c) && is_candidate(i))
+        } else {
+            idxs.rev().find(|i| current.map_or(true, |c| (*i as i64) < c) && is_candidate(i))
+        }
+    }
+
+    /// The next mark after the selected one, moving across items, that the filter and
+    /// `accept` let through, or the previous one when not `forward`. An item without a
+    /// selected mark counts as positioned before its first mark when going forward and
+    /// after its last one when going back.
+    fn step_mark(&self, forward: bool, accept: impl Fn(&ItemRecord, usize) -> bool) -> Option<(usize, usize)> {
+        let current = match self.selected_item {
+            Some(i) => (i as i64, self.selected_mark[i as usize].map_or(if forward { -1 } else { i64::MAX }, i64::from)),
+            None if forward => (-1, -1),
+            None => (i64::MAX, i64::MAX),
+        };
+        let mut positions = self
+            .items
+            .iter()
+            .enumerate()
+            .flat_map(|(i, item)| (0..item.found_mark_idxs.len()).map(move |m| (i, m)));
+        let is_candidate = |&(i, m): &(usize, usize)| self.filter.mark_matches(&self.items[i], m) && accept(&self.items[i], m);
+        if forward {
+            positions.find(|p| (p.0 as i64, p.1 as i64) > current && is_candidate(p))
+        } else {
+            positions.rev().find(|p| (p.0 as i64, p.1 as i64) < current && is_candidate(p))
+        }
+    }
+
+    /// Selects a mark of an item and tells the frontend.
+    fn select(&mut self, app: &tauri::AppHandle, item_idx: usize, mark_idx: Option<i32>) {
+        self.selected_item = Some(item_idx as i32);
+        self.selected_mark[item_idx] = mark_idx;
+        let _ = app.emit("sync_item_selection_state", (self.selected_item, mark_idx));
+    }
+
+    /// Puts the items in `order`, keeping the selected item and each item's selected mark.
+    fn sort(&mut self, order: ItemOrder, rules: &[ReplaceRule]) {
+        let selected_path = self
+            .selected_item
+            .and_then(|i| self.items.get(i as usize))
+            .map(|item| item.tg_rel_path.clone());
+        let mut entries: Vec<(i128, ItemRecord, Option<i32>)> = std::mem::take(&mut self.items)
+            .into_iter()
+            .zip(std::mem::take(&mut self.selected_mark))
+            .map(|(item, mark)| (item_order_key(&item, order, rules), item, mark))
+            .collect();
+        entries.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then_with(|| natord::compare(&a.1.tg_rel_path.to_string_lossy(), &b.1.tg_rel_path.to_string_lossy()))
+        });
+        for (_, item, mark) in entries {
+            self.items.push(item);
+            self.selected_mark.push(mark);
+        }
+        self.selected_item = selected_path
+            .and_then(|path| self.items.iter().position(|item| item.tg_rel_path == path))
+            .or(if self.items.is_empty() { None } else { Some(0) })
+            .map(|i| i as i32);
+    }
+}
+
+/// How many changes undo can take back.
+const HISTORY_LIMIT: usize = 200;
+
+/// The rules of a project along with the selection in them, as a rule edit leaves them.
+#[derive(Clone)]
+struct RulesSnapshot {
+    rules: Vec<ReplaceRule>,
+    active_rule_idxs: Vec<usize>,
+    selected_rule_idx: Option<i32>,
+    selected_term_idx: Option<i32>,
+    selected_opt_idx: Option<i32>,
+}
+
+impl RulesSnapshot {
+    fn of(proj_state: &ProjectState) -> Self {
+        RulesSnapshot {
+            rules: proj_state.rules.clone(),
+            active_rule_idxs: proj_state.active_rule_idxs.clone(),
+            selected_rule_idx: proj_state.selected_rule_idx,
+            selected_term_idx: proj_state.selected_term_idx,
+            selected_opt_idx: proj_state.selected_opt_idx,
+        }
+    }
+
+    fn restore(self, proj_state: &mut ProjectState) {
+        proj_state.rules = self.rules;
+        proj_state.active_rule_idxs = self.active_rule_idxs;
+        proj_state.selected_rule_idx = self.selected_rule_idx;
+        proj_state.selected_term_idx = self.selected_term_idx;
+        proj_state.selected_opt_idx = self.selected_opt_idx;
+    }
+}
+
+/// How a mark was reviewed, naming the option by its text.
+#[derive(Clone, PartialEq)]
+enum MarkState {
+    Undecided,
+    Kept,
+    Replaced(String),
+}
+
+impl MarkState {
+    fn of(item: &ItemRecord, mark_idx: usize) -> Self {
+        match item.selected_options[mark_idx] {
+            Some(opt_idx) => MarkState::Replaced(item.replace_options[mark_idx][opt_idx as usize].clone()),
+            None if item.decided[mark_idx] => MarkState::Kept,
+            None => MarkState::Undecided,
+        }
+    }
+}
+
+/// A change undo can take back.
+#[derive(Clone)]
+enum HistoryEntry {
+    /// A decision on a mark. The mark is found again by file, rule and first interval
+    /// and the option by its text, so the entry still applies after the items are
+    /// sorted or listed again with changed rules.
+    Decision { file: PathBuf, rule: String, first_idx: usize, before: MarkState, after: MarkState },
+    /// An edit of the rules, taken back by restoring them as they were.
+    Rules { before: RulesSnapshot, after: RulesSnapshot },
+}
+
+/// Undo and redo stacks shared by review decisions and rule edits.
+#[derive(Default)]
+struct History {
+    undo: VecDeque<HistoryEntry>,
+    redo: Vec<HistoryEntry>,
+}
+
+impl History {
+    fn record(&mut self, app: &tauri::AppHandle, entry: HistoryEntry) {
+        if self.undo.len() == HISTORY_LIMIT {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(entry);
+        self.redo.clear();
+        self.sync(app);
+    }
+
+    /// Records a rule edit unless the command left the rules as they were.
+    fn record_rules(&mut self, app: &tauri::AppHandle, before: RulesSnapshot, proj_state: &ProjectState) {
+        if before.rules != proj_state.rules || before.active_rule_idxs != proj_state.active_rule_idxs {
+            self.record(app, HistoryEntry::Rules { before, after: RulesSnapshot::of(proj_state) });
+        }
+    }
+
+    fn clear(&mut self, app: &tauri::AppHandle) {
+        self.undo.clear();
+        self.redo.clear();
+        self.sync(app);
+    }
+
+    fn sync(&self, app: &tauri::AppHandle) {
+        let _ = app.emit("sync_history_state", (!self.undo.is_empty(), !self.redo.is_empty()));
+    }
+}
+
+/// Applies one side of a history entry, `undo` picking the state before the change.
+/// Returns false when the entry's mark is no longer listed or lost the option.
+fn apply_history_entry(
+    entry: &HistoryEntry,
+    undo: bool,
+    app: &tauri::AppHandle,
+    proj_state: &mut ProjectState,
+    session: &mut SessionItems,
+) -> bool {
+    match entry {
+        HistoryEntry::Decision { file, rule, first_idx, before, after } => {
+            let found = session.items.iter().enumerate().find_map(|(i, item)| {
+                if &item.tg_rel_path != file {
+                    return None;
+                }
+                (0..item.found_mark_idxs.len())
+                    .find(|&m| &item.found_mark_rules[m] == rule && item.found_mark_idxs[m][0] == *first_idx)
+                    .map(|m| (i, m))
+            });
+            let Some((item_index, mark_index)) = found else {
+                return false;
+            };
+            let item = &mut session.items[item_index];
+            let (selected, decided) = match if undo { before } else { after } {
+                MarkState::Undecided => (None, false),
+                MarkState::Kept => (None, true),
+                MarkState::Replaced(option) => match item.replace_options[mark_index].iter().position(|o| o == option) {
+                    Some(opt_idx) => (Some(opt_idx as i32), true),
+                    None => return false,
+                },
+            };
+            item.selected_options[mark_index] = selected;
+            item.decided[mark_index] = decided;
+            item.dirty = item.selected_options != item.original_options;
+            let _ = app.emit("sync_item_options", (item_index, item.selected_options.clone(), item.decided.clone(), item.dirty));
+            session.select(app, item_index, Some(mark_index as i32));
+        }
+        HistoryEntry::Rules { before, after } => {
+            (if undo { before } else { after }).clone().restore(proj_state);
+            let _ = app.emit("sync_app_state", proj_state.clone());
+        }
+    }
+    true
+}
+
+/// Sort key of an item under `order`, smaller keys come first.
+fn item_order_key(item: &ItemRecord, order: ItemOrder, rules: &[ReplaceRule]) -> i128 {
+    match order {
+        ItemOrder::Path => 0,
+        ItemOrder::MarkCount => -(item.found_mark_idxs.len() as i128),
+        ItemOrder::Modified => fs::metadata(&item.tg_file)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| -(d.as_nanos() as i128)),
+        ItemOrder::Rule => item
+            .found_mark_rules
+            .iter()
+            .filter_map(|name| rules.iter().position(|r| &r.rule_name == name))
+            .min()
+            .map_or(rules.len() as i128, |i| i as i128),
+        ItemOrder::Undecided => -(item.decided.iter().filter(|d| !**d).count() as i128),
+    }
 }
 
 /// Internal function that builds a `FadeOut` object.
@@ -339,126 +582,18 @@ where
     }
 }
 
-fn parse_textgrid(tg_file: PathBuf) -> Result<TextGrid, String> {
-    let file = File::open(tg_file).map_err(|e| e.to_string())?;
-    let mut reader = BufReader::new(file);
-    let mut buffer = Vec::new();
-    reader.read_to_end(&mut buffer).map_err(|e| e.to_string())?;
-
-    let mut detector = EncodingDetector::new();
-    detector.feed(&buffer, true);
-    let encoding = detector.guess(None, true);
-
-    let (decoded, _, _) = encoding.decode(&buffer);
-    let content = decoded.to_string();
-
-    let parsed = TextGridParser::parse(Rule::file, &content).map_err(|e| e.to_string())?;
-
-    let mut tg = TextGrid::default();
-
-    for record in parsed {
-        if record.as_rule() != Rule::file { continue; }
-        for content in record.into_inner() {
-            if content.as_rule() != Rule::items { continue; }
-            for sub_content in content.into_inner() {
-                if sub_content.as_rule() != Rule::single_item { continue; }
-                let mut tg_item = TextGridIntervals::default();
-                for sub_item in sub_content.into_inner() {
-                    match sub_item.as_rule() {
-                        Rule::property => {
-                            let mut inner_rules = sub_item.into_inner();
-                            let name: &str = inner_rules.next().unwrap().as_str();
-                            if name == "name" {
-                                tg_item.name = inner_rules.next().unwrap().as_str().to_string();
-                            }
-                        }
-                        Rule::single_interval => {
-                            let mut tg_interval = TextGridInterval::default();
-                            for interval in sub_item.into_inner() {
-                                if interval.as_rule() == Rule::property {
-                                    let mut inner_rules = interval.into_inner();
-                                    let name: &str = inner_rules.next().unwrap().as_str();
-                                    let value = inner_rules.next().unwrap().as_str();
-                                    match name {
-                                        "xmin" => {
-                                            tg_interval.xmin =
-                                                value.parse::<f32>().map_err(|e| e.to_string())?
-                                        }
-                                        "xmax" => {
-                                            tg_interval.xmax =
-                                                value.parse::<f32>().map_err(|e| e.to_string())?
-                                        }
-                                        "text" => tg_interval.text = value.to_string(),
-                                        _ => {}
-                                    }
-                                }
-                            }
-                            tg_item.intervals.push(tg_interval);
-                        }
-                        _ => {}
-                    }
-                }
-                tg.items.push(tg_item);
-            }
-        }
-    }
-    Ok(tg)
-}
-
-fn find_marks(rule: &ReplaceRule, tg: &TextGrid) -> (Vec<usize>, Vec<String>) {
-    let mut found_mark_idxs = Vec::new();
-    let mut found_mark_titles = Vec::new();
-    let tg_words = &tg.items.get(0).unwrap().intervals;
-    let tg_phones = &tg.items.get(if tg.items.len() > 1 { 1 } else { 0 }).unwrap().intervals;
-    let mut corr_words = Vec::new();
-    let mut i = 0;
-    for (w_i, word) in tg_words.iter().enumerate() {
-        while i < tg_phones.len() {
-            if (word.xmax.min(tg_phones[i].xmax) - word.xmin.max(tg_phones[i].xmin)) / (tg_phones[i].xmax - tg_phones[i].xmin) > 0.8 {
-                corr_words.push((w_i, word.text.clone()));
-                i += 1;
-            } else {
-                break;
-            }
-        }
-    }
-    if rule.term_seq_length >= 1 {
-        let find_word_tuple_set: HashSet<Vec<String>> = rule.search_terms.iter().map(|s| s.split_whitespace().map(String::from).collect()).collect();
-        if tg_phones.len() >= rule.term_seq_length {
-            for (i, item_win) in tg_phones.windows(rule.term_seq_length).enumerate() {
-                if find_word_tuple_set.contains(&item_win.iter().map(|s| s.text.clone()).collect::<Vec<String>>()) {
-                    found_mark_idxs.push(i);
-                    let mut title = String::new();
-                    if corr_words.len() == tg_phones.len() {
-                        let mut rel_words: Vec<(usize, String)> = corr_words[i..i + rule.term_seq_length].to_vec();
-                        rel_words.dedup_by_key(|x| x.0);
-                        title.push_str(format!("({}) ", rel_words.iter().map(|w| w.1.split(":").next().unwrap().to_string()).collect::<Vec<String>>().join(" ")).as_str());
-                    }
-                    for j in max(0, i as i32 - 2) as usize..i {
-                        title.push_str((tg_phones[j].text.clone() + " ").as_str());
-                    }
-                    title.push_str(format!("[{}] ", tg_phones[i..i + rule.term_seq_length].iter().map(|w| w.text.clone()).collect::<Vec<String>>().join(" ")).as_str());
-                    for j in min(i + rule.term_seq_length, tg_phones.len() - 1)..min(i + rule.term_seq_length + 2, tg_phones.len()) {
-                        title.push_str((tg_phones[j].text.clone() + " ").as_str());
-                    }
-                    found_mark_titles.push(title.trim().to_string());
-                }
-            }
-        }
-    }
-    (found_mark_idxs, found_mark_titles)
-}
-
 #[tauri::command]
 fn add_rule(
     rule_name: String,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     if rule_name.trim().is_empty() {
         return Ok(());
     }
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     let name_vec: Vec<&str> = rule_name.split(",").collect();
     let name: String;
     let seq_len: usize;
@@ -494,12 +629,20 @@ fn add_rule(
             term_seq_length: seq_len,
             search_terms: Vec::new().into_iter().collect(),
             replace_options: Vec::new(),
+            target_tier: TargetTier::default(),
+            left_contexts: Vec::new(),
+            right_contexts: Vec::new(),
+            word_filter: Vec::new(),
+            word_position: WordPosition::default(),
+            span_mode: SpanMode::default(),
+            skip_silence: false,
         });
         proj_state.selected_rule_idx = Some((proj_state.rules.len() - 1) as i32);
         proj_state.selected_term_idx = None;
         proj_state.selected_opt_idx = None;
         let _ = app.emit("sync_app_state", proj_state.clone());
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -508,12 +651,14 @@ fn rename_rule(
     rule_index: i32,
     new_name: String,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     if new_name.trim().is_empty() || new_name.contains(",") {
         return Err("Invalid rule name".into());
     }
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     if rule_index >= 0
         && rule_index < proj_state.rules.len() as i32
         && !proj_state
@@ -528,6 +673,7 @@ fn rename_rule(
             .rule_name = new_name;
         let _ = app.emit("sync_app_state", proj_state.clone());
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -535,11 +681,20 @@ fn rename_rule(
 fn remove_rule(
     rule_index: i32,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     if rule_index >= 0 && rule_index < proj_state.rules.len() as i32 {
         proj_state.rules.remove(rule_index as usize);
+        let removed = rule_index as usize;
+        proj_state.active_rule_idxs.retain(|&i| i != removed);
+        for i in proj_state.active_rule_idxs.iter_mut() {
+            if *i > removed {
+                *i -= 1;
+            }
+        }
         if let Some(active_rule) = proj_state.selected_rule_idx {
             if active_rule as usize >= proj_state.rules.len() {
                 proj_state.selected_rule_idx = if proj_state.rules.is_empty() { None } else { Some((proj_state.rules.len() - 1) as i32) };
@@ -549,6 +704,30 @@ fn remove_rule(
         }
         let _ = app.emit("sync_app_state", proj_state.clone());
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
+    Ok(())
+}
+
+#[tauri::command]
+fn toggle_active_rule(
+    rule_index: i32,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
+    if rule_index >= 0 && rule_index < proj_state.rules.len() as i32 {
+        let rule_index = rule_index as usize;
+        if let Some(pos) = proj_state.active_rule_idxs.iter().position(|&i| i == rule_index) {
+            proj_state.active_rule_idxs.remove(pos);
+        } else {
+            proj_state.active_rule_idxs.push(rule_index);
+            proj_state.active_rule_idxs.sort();
+        }
+        let _ = app.emit("sync_app_state", proj_state.clone());
+    }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -556,7 +735,7 @@ fn remove_rule(
 fn select_rule(
     rule_index: i32,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
 ) -> Result<(), String> {
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
     if rule_index < 0 || rule_index >= proj_state.rules.len() as i32 {
@@ -574,18 +753,120 @@ fn select_rule(
     Ok(())
 }
 
+#[tauri::command]
+fn set_rule_target_tier(
+    rule_index: i32,
+    target_tier: String,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
+    if rule_index >= 0 && rule_index < proj_state.rules.len() as i32 {
+        let target_tier = TargetTier::from_name(&target_tier);
+        let rule = proj_state.rules.get_mut(rule_index as usize).unwrap();
+        if rule.target_tier != target_tier {
+            rule.target_tier = target_tier;
+            let _ = app.emit("sync_app_state", proj_state.clone());
+        }
+    }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_rule_contexts(
+    rule_index: i32,
+    left_contexts: String,
+    right_contexts: String,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
+    if rule_index >= 0 && rule_index < proj_state.rules.len() as i32 {
+        let split_contexts = |contexts: &str| -> Result<Vec<String>, String> {
+            contexts
+                .split("|")
+                .map(|c| c.split_whitespace().collect::<Vec<&str>>().join(" "))
+                .filter(|c| !c.is_empty())
+                .map(|c| ContextPattern::parse(&c, &proj_state.phoneme_classes).map(|_| c).map_err(|e| e.to_string()))
+                .collect()
+        };
+        let left_contexts = split_contexts(&left_contexts)?;
+        let right_contexts = split_contexts(&right_contexts)?;
+        let rule = proj_state.rules.get_mut(rule_index as usize).unwrap();
+        rule.left_contexts = left_contexts;
+        rule.right_contexts = right_contexts;
+        let _ = app.emit("sync_app_state", proj_state.clone());
+    }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_rule_word_condition(
+    rule_index: i32,
+    word_filter: String,
+    word_position: WordPosition,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
+    if rule_index >= 0 && rule_index < proj_state.rules.len() as i32 {
+        let word_filter: Vec<String> = word_filter.split_whitespace().map(String::from).collect();
+        for word in word_filter.iter() {
+            TokenPattern::parse(word, &proj_state.phoneme_classes).map_err(|e| e.to_string())?;
+        }
+        let rule = proj_state.rules.get_mut(rule_index as usize).unwrap();
+        rule.word_filter = word_filter;
+        rule.word_position = word_position;
+        let _ = app.emit("sync_app_state", proj_state.clone());
+    }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
+    Ok(())
+}
+
+#[tauri::command]
+fn set_rule_span(
+    rule_index: i32,
+    span_mode: SpanMode,
+    skip_silence: bool,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
+    if rule_index >= 0 && rule_index < proj_state.rules.len() as i32 {
+        let rule = proj_state.rules.get_mut(rule_index as usize).unwrap();
+        rule.span_mode = span_mode;
+        rule.skip_silence = skip_silence;
+        let _ = app.emit("sync_app_state", proj_state.clone());
+    }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
+    Ok(())
+}
+
 #[tauri::command]
 fn add_search_term(
     term: String,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     if term.trim().is_empty() {
         return Ok(());
     }
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     if let Some(rule_index) = proj_state.selected_rule_idx {
         let term_seq_length = proj_state.rules[rule_index as usize].term_seq_length;
+        TermPattern::parse(&term, &proj_state.phoneme_classes).map_err(|e| e.to_string())?;
         let search_terms = &mut proj_state
             .rules
             .get_mut(rule_index as usize)
@@ -605,6 +886,7 @@ fn add_search_term(
             let _ = app.emit("sync_app_state", proj_state.clone());
         }
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -612,9 +894,11 @@ fn add_search_term(
 fn remove_search_term(
     term_index: i32,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     if let Some(rule_index) = proj_state.selected_rule_idx {
         let search_terms = &proj_state
             .rules
@@ -641,6 +925,7 @@ fn remove_search_term(
             let _ = app.emit("sync_app_state", proj_state.clone());
         }
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -649,14 +934,17 @@ fn rename_search_term(
     term_index: i32,
     new_term: String,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     if new_term.trim().is_empty() {
         return Ok(());
     }
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     if let Some(rule_index) = proj_state.selected_rule_idx {
         let term_seq_length = proj_state.rules[rule_index as usize].term_seq_length;
+        TermPattern::parse(&new_term, &proj_state.phoneme_classes).map_err(|e| e.to_string())?;
         let search_terms = &mut proj_state
             .rules
             .get_mut(rule_index as usize)
@@ -673,6 +961,7 @@ fn rename_search_term(
             let _ = app.emit("sync_app_state", proj_state.clone());
         }
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -680,7 +969,7 @@ fn rename_search_term(
 fn select_search_term(
     term_index: i32,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
 ) -> Result<(), String> {
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
     if let Some(rule_index) = proj_state.selected_rule_idx {
@@ -702,14 +991,15 @@ fn select_search_term(
 fn add_replace_option(
     replace_opt: String,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     if replace_opt.trim().is_empty() {
         return Ok(());
     }
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     if let Some(rule_index) = proj_state.selected_rule_idx {
-        let term_seq_length = proj_state.rules[rule_index as usize].term_seq_length;
         let replace_options = &mut proj_state
             .rules
             .get_mut(rule_index as usize)
@@ -721,14 +1011,13 @@ fn add_replace_option(
             proj_state.selected_opt_idx = Some(replace_options.iter().position(|w| w == &replace_opt).unwrap() as i32);
             let _ = app.emit("sync_app_selection_state", (proj_state.selected_rule_idx, proj_state.selected_term_idx, proj_state.selected_opt_idx));
         } else {
-            if opt_vec.len() != term_seq_length {
-                return Err("Invalid search term".into());
-            }
+            parse_replace_option(&replace_opt).map_err(|e| e.to_string())?;
             replace_options.push(replace_opt);
             proj_state.selected_opt_idx = Some(replace_options.len() as i32 - 1);
             let _ = app.emit("sync_app_state", proj_state.clone());
         }
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -736,9 +1025,11 @@ fn add_replace_option(
 fn remove_replace_option(
     opt_index: i32,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     if let Some(rule_index) = proj_state.selected_rule_idx {
         let replace_options = &proj_state
             .rules
@@ -765,6 +1056,7 @@ fn remove_replace_option(
             let _ = app.emit("sync_app_state", proj_state.clone());
         }
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -773,14 +1065,15 @@ fn rename_replace_option(
     opt_index: i32,
     new_opt: String,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    history: State<'_, Mutex<History>>,
 ) -> Result<(), String> {
     if new_opt.trim().is_empty() {
         return Ok(());
     }
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let before = RulesSnapshot::of(&proj_state);
     if let Some(rule_index) = proj_state.selected_rule_idx {
-        let term_seq_length = proj_state.rules[rule_index as usize].term_seq_length;
         let replace_options = &mut proj_state
             .rules
             .get_mut(rule_index as usize)
@@ -788,15 +1081,16 @@ fn rename_replace_option(
             .replace_options;
         let opt_vec: Vec<&str> = new_opt.split_whitespace().map(|s| s.trim()).filter(|s| !s.is_empty()).collect();
         let new_opt = opt_vec.join(" ");
+        parse_replace_option(&new_opt).map_err(|e| e.to_string())?;
         if opt_index >= 0
             && opt_index < replace_options.len() as i32
-            && opt_vec.len() == term_seq_length
             && !replace_options.iter().any(|w| w == &new_opt)
         {
             replace_options[opt_index as usize] = new_opt;
             let _ = app.emit("sync_app_state", proj_state.clone());
         }
     }
+    history.lock().map_err(|e| e.to_string())?.record_rules(&app, before, &proj_state);
     Ok(())
 }
 
@@ -804,7 +1098,7 @@ fn rename_replace_option(
 fn select_replace_option(
     opt_index: i32,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
 ) -> Result<(), String> {
     let mut proj_state = state.lock().map_err(|e| e.to_string())?;
     if let Some(rule_index) = proj_state.selected_rule_idx {
@@ -848,18 +1142,9 @@ fn select_item(
 #[tauri::command]
 fn next_item(app: tauri::AppHandle, state: State<'_, Mutex<SessionItems>>) -> Result<(), String> {
     let mut session = state.lock().map_err(|e| e.to_string())?;
-    let new_val = if session.selected_item < Some(session.items.len() as i32 - 1) { Some(session.selected_item.unwrap() + 1) } else { session.selected_item };
-    if session.selected_item != new_val {
-        session.selected_item = new_val;
-        let _ = app.emit(
-            "sync_item_selection_state",
-            (
-                session.selected_item,
-                session
-                    .selected_item
-                    .and_then(|i| Some(session.selected_mark[i as usize])),
-            ),
-        );
+    if let Some(item_index) = session.step_item(true) {
+        let mark_index = session.visible_mark(item_index);
+        session.select(&app, item_index, mark_index);
     }
     Ok(())
 }
@@ -867,18 +1152,9 @@ fn next_item(app: tauri::AppHandle, state: State<'_, Mutex<SessionItems>>) -> Re
 #[tauri::command]
 fn prev_item(app: tauri::AppHandle, state: State<'_, Mutex<SessionItems>>) -> Result<(), String> {
     let mut session = state.lock().map_err(|e| e.to_string())?;
-    let new_val = if session.selected_item > Some(0) { Some(session.selected_item.unwrap() - 1) } else { session.selected_item };
-    if session.selected_item != new_val {
-        session.selected_item = new_val;
-        let _ = app.emit(
-            "sync_item_selection_state",
-            (
-                session.selected_item,
-                session
-                    .selected_item
-                    .and_then(|i| Some(session.selected_mark[i as usize])),
-            ),
-        );
+    if let Some(item_index) = session.step_item(false) {
+        let mark_index = session.visible_mark(item_index);
+        session.select(&app, item_index, mark_index);
     }
     Ok(())
 }
@@ -903,20 +1179,8 @@ fn select_mark(
 #[tauri::command]
 fn next_mark(app: tauri::AppHandle, state: State<'_, Mutex<SessionItems>>) -> Result<(), String> {
     let mut session = state.lock().map_err(|e| e.to_string())?;
-    if let Some(item_index) = session.selected_item {
-        let found_mark_idxs = &session.items[item_index as usize].found_mark_idxs;
-        if let Some(mark_index) = session.selected_mark[item_index as usize] {
-            if mark_index < found_mark_idxs.len() as i32 - 1 {
-                session.selected_mark[item_index as usize] = Some(mark_index + 1);
-                let _ = app.emit("sync_item_selection_state", (session.selected_item, mark_index + 1));
-            } else if item_index < session.items.len() as i32 - 1
-                && mark_index == found_mark_idxs.len() as i32 - 1
-            {
-                session.selected_item = Some(item_index + 1);
-                session.selected_mark[(item_index + 1) as usize] = Some(0);
-                let _ = app.emit("sync_item_selection_state", (item_index + 1, 0));
-            }
-        }
+    if let Some((item_index, mark_index)) = session.step_mark(true, |_, _| true) {
+        session.select(&app, item_index, Some(mark_index as i32));
     }
     Ok(())
 }
@@ -924,35 +1188,90 @@ fn next_mark(app: tauri::AppHandle, state: State<'_, Mutex<SessionItems>>) -> Re
 #[tauri::command]
 fn prev_mark(app: tauri::AppHandle, state: State<'_, Mutex<SessionItems>>) -> Result<(), String> {
     let mut session = state.lock().map_err(|e| e.to_string())?;
-    if let Some(item_index) = session.selected_item {
-        if let Some(mark_index) = session.selected_mark[item_index as usize] {
-            if mark_index > 0 {
-                session.selected_mark[item_index as usize] = Some(mark_index - 1);
-                let _ = app.emit("sync_item_selection_state", (session.selected_item, mark_index - 1));
-            } else if item_index > 0 && mark_index == 0 {
-                session.selected_item = Some(item_index - 1);
-                session.selected_mark[(item_index - 1) as usize] = Some(session.items[(item_index - 1) as usize].found_mark_idxs.len() as i32 - 1);
-                let _ = app.emit("sync_item_selection_state", (item_index - 1, session.selected_mark[session.selected_item.unwrap() as usize]));
-            }
-        }
+    if let Some((item_index, mark_index)) = session.step_mark(false, |_, _| true) {
+        session.select(&app, item_index, Some(mark_index as i32));
     }
     Ok(())
 }
 
+#[tauri::command]
+fn next_undecided(app: tauri::AppHandle, state: State<'_, Mutex<SessionItems>>) -> Result<(), String> {
+    let mut session = state.lock().map_err(|e| e.to_string())?;
+    if let Some((item_index, mark_index)) = session.step_mark(true, |item, m| !item.decided[m]) {
+        session.select(&app, item_index, Some(mark_index as i32));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn prev_undecided(app: tauri::AppHandle, state: State<'_, Mutex<SessionItems>>) -> Result<(), String> {
+    let mut session = state.lock().map_err(|e| e.to_string())?;
+    if let Some((item_index, mark_index)) = session.step_mark(false, |item, m| !item.decided[m]) {
+        session.select(&app, item_index, Some(mark_index as i32));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn set_item_filter(
+    filter: ItemFilter,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<SessionItems>>,
+) -> Result<Option<FilteredView>, String> {
+    let mut session = state.lock().map_err(|e| e.to_string())?;
+    session.filter = filter;
+    let selected_visible = session.selected_item.is_some_and(|i| session.filter.item_matches(&session.items[i as usize]));
+    if !selected_visible {
+        session.selected_item = None;
+        if let Some(item_index) = session.step_item(true) {
+            let mark_index = session.visible_mark(item_index);
+            session.select(&app, item_index, mark_index);
+        } else {
+            let _ = app.emit("sync_item_selection_state", (None::<i32>, None::<i32>));
+        }
+    } else if let Some(item_index) = session.selected_item {
+        let mark_index = session.visible_mark(item_index as usize);
+        session.select(&app, item_index as usize, mark_index);
+    }
+    Ok(session.filtered_view())
+}
+
+#[tauri::command]
+fn filtered_items(state: State<'_, Mutex<SessionItems>>) -> Result<Option<FilteredView>, String> {
+    let session = state.lock().map_err(|e| e.to_string())?;
+    Ok(session.filtered_view())
+}
+
 #[tauri::command]
 fn choose_a_replace_option(
     opt_index: i32,
+    app: tauri::AppHandle,
     state: State<'_, Mutex<SessionItems>>,
-) -> Result<Option<(Vec<Option<i32>>, bool)>, String> {
+    history: State<'_, Mutex<History>>,
+) -> Result<Option<(Vec<Option<i32>>, Vec<bool>, bool)>, String> {
     let mut session = state.lock().map_err(|e| e.to_string())?;
     if let Some(item_index) = session.selected_item {
         if let Some(mark_index) = session.selected_mark[item_index as usize] {
             let item = session.items.get_mut(item_index as usize).unwrap();
-            let new_val = if opt_index > -1 { Some(opt_index) } else { None };
-            if item.selected_options[mark_index as usize] != new_val {
-                item.selected_options[mark_index as usize] = new_val;
+            let mark_idx = mark_index as usize;
+            let new_val = if opt_index > -1 && (opt_index as usize) < item.replace_options[mark_idx].len() { Some(opt_index) } else { None };
+            // Keeping a mark as it is decides it as much as choosing an option does.
+            if item.selected_options[mark_idx] != new_val || !item.decided[mark_idx] {
+                let before = MarkState::of(item, mark_idx);
+                item.selected_options[mark_idx] = new_val;
+                item.decided[mark_idx] = true;
+                history.lock().map_err(|e| e.to_string())?.record(
+                    &app,
+                    HistoryEntry::Decision {
+                        file: item.tg_rel_path.clone(),
+                        rule: item.found_mark_rules[mark_idx].clone(),
+                        first_idx: item.found_mark_idxs[mark_idx][0],
+                        before,
+                        after: MarkState::of(item, mark_idx),
+                    },
+                );
                 item.dirty = item.selected_options != item.original_options;
-                return Ok(Some((item.selected_options.clone(), item.dirty)));
+                return Ok(Some((item.selected_options.clone(), item.decided.clone(), item.dirty)));
             }
         }
     }
@@ -960,7 +1279,48 @@ fn choose_a_replace_option(
 }
 
 #[tauri::command]
-fn get_config_state(state: State<'_, Mutex<AppProjectState>>) -> Result<AppProjectState, String> {
+fn undo(
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+    session_state: State<'_, Mutex<SessionItems>>,
+    history: State<'_, Mutex<History>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let mut session = session_state.lock().map_err(|e| e.to_string())?;
+    let mut history = history.lock().map_err(|e| e.to_string())?;
+    // Decisions on marks that are no longer listed cannot be taken back and are dropped.
+    while let Some(entry) = history.undo.pop_back() {
+        if apply_history_entry(&entry, true, &app, &mut proj_state, &mut session) {
+            history.redo.push(entry);
+            break;
+        }
+    }
+    history.sync(&app);
+    Ok(())
+}
+
+#[tauri::command]
+fn redo(
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+    session_state: State<'_, Mutex<SessionItems>>,
+    history: State<'_, Mutex<History>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    let mut session = session_state.lock().map_err(|e| e.to_string())?;
+    let mut history = history.lock().map_err(|e| e.to_string())?;
+    while let Some(entry) = history.redo.pop() {
+        if apply_history_entry(&entry, false, &app, &mut proj_state, &mut session) {
+            history.undo.push_back(entry);
+            break;
+        }
+    }
+    history.sync(&app);
+    Ok(())
+}
+
+#[tauri::command]
+fn get_config_state(state: State<'_, Mutex<ProjectState>>) -> Result<ProjectState, String> {
     Ok(state.lock().map_err(|e| e.to_string())?.clone())
 }
 #[tauri::command]
@@ -983,7 +1343,7 @@ fn open_folder(
     folder_path: PathBuf,
     target: &str,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
 ) -> Result<(), String> {
     // let folder_path = app
     //     .dialog()
@@ -1015,88 +1375,285 @@ fn open_folder(
 }
 
 #[tauri::command]
-fn list_items(
+fn set_tier_names(
+    word_tier_names: String,
+    phone_tier_names: String,
     app: tauri::AppHandle,
-    project_state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+) -> Result<(), String> {
+    let split_names = |names: &str| -> Vec<String> {
+        names.split(",").map(|s| s.trim()).filter(|s| !s.is_empty()).map(String::from).collect()
+    };
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    proj_state.word_tier_names = split_names(&word_tier_names);
+    proj_state.phone_tier_names = split_names(&phone_tier_names);
+    let _ = app.emit("sync_app_state", proj_state.clone());
+    Ok(())
+}
+
+#[tauri::command]
+fn set_scan_filter(
+    include_patterns: String,
+    exclude_patterns: String,
+    recursive_scan: bool,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+) -> Result<(), String> {
+    let split_patterns = |patterns: &str| -> Vec<String> { patterns.split_whitespace().map(String::from).collect() };
+    let include_patterns = split_patterns(&include_patterns);
+    let exclude_patterns = split_patterns(&exclude_patterns);
+    TextGridFilter::new(&include_patterns, &exclude_patterns, recursive_scan).map_err(|e| e.to_string())?;
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    proj_state.include_patterns = include_patterns;
+    proj_state.exclude_patterns = exclude_patterns;
+    proj_state.recursive_scan = recursive_scan;
+    let _ = app.emit("sync_app_state", proj_state.clone());
+    Ok(())
+}
+
+#[tauri::command]
+fn set_item_order(
+    item_order: ItemOrder,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
     session_state: State<'_, Mutex<SessionItems>>,
 ) -> Result<(), String> {
-    let proj_state = project_state.lock().map_err(|e| e.to_string())?;
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
     let mut sess_state = session_state.lock().map_err(|e| e.to_string())?;
-    if proj_state.tg_folder == None {
+    proj_state.item_order = item_order;
+    sess_state.sort(item_order, &proj_state.rules);
+    let _ = app.emit("sync_app_state", proj_state.clone());
+    let _ = app.emit("sync_session_state", sess_state.clone());
+    Ok(())
+}
+
+#[tauri::command]
+fn set_silence_marks(
+    silence_marks: String,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    proj_state.silence_marks = silence_marks
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+    let _ = app.emit("sync_app_state", proj_state.clone());
+    Ok(())
+}
+
+#[tauri::command]
+fn set_phoneme_class(
+    definition: String,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+) -> Result<(), String> {
+    let (name, members) = parse_class_definition(&definition).map_err(|e| e.to_string())?;
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    proj_state.phoneme_classes.insert(name, members);
+    let _ = app.emit("sync_app_state", proj_state.clone());
+    Ok(())
+}
+
+#[tauri::command]
+fn remove_phoneme_class(
+    name: String,
+    app: tauri::AppHandle,
+    state: State<'_, Mutex<ProjectState>>,
+) -> Result<(), String> {
+    let mut proj_state = state.lock().map_err(|e| e.to_string())?;
+    if proj_state.phoneme_classes.remove(&name).is_some() {
+        let _ = app.emit("sync_app_state", proj_state.clone());
+    }
+    Ok(())
+}
+
+/// Progress of a running scan, emitted as `scan_progress`.
+#[derive(Clone, serde::Serialize)]
+struct ScanProgress {
+    files_done: usize,
+    files_total: usize,
+    marks_found: usize,
+}
+
+/// How a scan ended, emitted as `list_item_done`.
+#[derive(Clone, Default, serde::Serialize)]
+struct ScanOutcome {
+    cancelled: bool,
+    error: Option<String>,
+    /// Saved decisions the scan could not restore.
+    unrestored: Vec<String>,
+}
+
+/// Lets `cancel_scan` reach the scan running in the background.
+#[derive(Default)]
+struct ScanControl {
+    running: AtomicBool,
+    cancelled: AtomicBool,
+    /// Counts the projects loaded or cleared, a scan started under an earlier one
+    /// leaves the session alone.
+    generation: AtomicUsize,
+}
+
+impl ScanControl {
+    /// Cancels the running scan and keeps it from writing to the session even when it
+    /// is already past its last cancellation check. Call with the session locked.
+    fn invalidate(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[tauri::command]
+fn list_items(
+    app: tauri::AppHandle,
+    project_state: State<'_, Mutex<ProjectState>>,
+    app_settings: State<'_, Mutex<AppSettings>>,
+    scan_control: State<'_, ScanControl>,
+) -> Result<(), String> {
+    // Taken together with the project, which is only replaced under its lock.
+    let (proj_state, generation) = {
+        let proj_state = project_state.lock().map_err(|e| e.to_string())?;
+        (proj_state.clone(), scan_control.generation.load(Ordering::SeqCst))
+    };
+    let skip_broken_files = app_settings.lock().map_err(|e| e.to_string())?.skip_broken_files;
+    let Some(tg_folder) = proj_state.tg_folder.clone() else {
         return Err("TextGrid folder must be set.".into());
+    };
+    if proj_state.session_rule_idxs().is_empty() {
+        return Err("Must select a rule first.".into());
     }
-    match proj_state.selected_rule_idx {
-        Some(idx) => {
-            if idx >= proj_state.rules.len() as i32 {
-                return Err("Internal error: selected rule index out of range.".into());
+    let filter = proj_state.textgrid_filter().map_err(|e| e.to_string())?;
+    proj_state.compile_session_rules().map_err(|e| e.to_string())?;
+    if scan_control.running.swap(true, Ordering::SeqCst) {
+        return Err("A scan is already running.".into());
+    }
+    scan_control.cancelled.store(false, Ordering::SeqCst);
+    thread::spawn(move || {
+        // A panic in a worker must not leave the scan marked as running.
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| run_scan(&app, &proj_state, &tg_folder, &filter, skip_broken_files, generation)))
+            .unwrap_or_else(|_| Err("The scan stopped unexpectedly.".into()))
+            .unwrap_or_else(|e| ScanOutcome { error: Some(e), ..Default::default() });
+        app.state::<ScanControl>().running.store(false, Ordering::SeqCst);
+        let _ = app.emit("list_item_done", outcome);
+    });
+    Ok(())
+}
+
+/// Scans the TextGrid folder in parallel and swaps the result into the session at the
+/// end. Neither the project nor the session is locked while files are read, decisions
+/// made in the meantime are carried over to the new items.
+fn run_scan(
+    app: &tauri::AppHandle,
+    proj_state: &ProjectState,
+    tg_folder: &Path,
+    filter: &TextGridFilter,
+    skip_broken_files: bool,
+    generation: usize,
+) -> Result<ScanOutcome, String> {
+    let control = app.state::<ScanControl>();
+    let compiled_rules = proj_state.compile_session_rules().map_err(|e| e.to_string())?;
+    let paths = find_textgrids(tg_folder, filter).map_err(|e| e.to_string())?;
+    let files_total = paths.len();
+    let files_done = AtomicUsize::new(0);
+    let marks_found = AtomicUsize::new(0);
+    let report_every = max(1, files_total / 100);
+    let results: Vec<Result<Option<ItemRecord>, Box<TextGridError>>> = paths
+        .par_iter()
+        .map(|path| {
+            if control.cancelled.load(Ordering::Relaxed) {
+                return Ok(None);
             }
-        }
-        None => return Err("Must select a rule first.".into()),
-    }
-    sess_state.items = Vec::new();
-    sess_state.selected_item = None;
-    sess_state.selected_mark = Vec::new();
-    let tg_folder = proj_state.tg_folder.clone().unwrap();
-    let wav_folder = &proj_state.wav_folder;
-    let active_rule = proj_state
-        .rules
-        .get(proj_state.selected_rule_idx.unwrap() as usize)
-        .unwrap();
-    match fs::read_dir(tg_folder) {
-        Ok(entries) => {
-            for entry in entries {
-                match entry {
-                    Ok(entry) => {
-                        let path = entry.path();
-                        if path
-                            .extension()
-                            .and_then(|ext| ext.to_str())
-                            .map(|ext| ext.to_lowercase())
-                            == Some("textgrid".into())
-                        {
-                            let tg = parse_textgrid(path.clone())?;
-                            let (found_mark_idxs, found_mark_titles) = find_marks(active_rule, &tg);
-                            if found_mark_idxs.len() > 0 {
-                                let item_record = ItemRecord {
-                                    tg_file: path.clone(),
-                                    tg_stem: path
-                                        .file_stem()
-                                        .unwrap()
-                                        .to_str()
-                                        .unwrap()
-                                        .to_string(),
-                                    tg_content: tg,
-                                    wav_file: if let Some(wav_folder_unwrap) = wav_folder {
-                                        path.clone()
-                                            .with_extension("wav")
-                                            .file_name()
-                                            .and_then(|wav_fn| Some(wav_folder_unwrap.join(wav_fn)))
-                                    } else {
-                                        None
-                                    },
-                                    selected_options: vec![None; found_mark_idxs.len()],
-                                    original_options: vec![None; found_mark_idxs.len()],
-                                    term_seq_length: active_rule.term_seq_length,
-                                    replace_options: active_rule.replace_options.clone(),
-                                    found_mark_idxs,
-                                    found_mark_titles,
-                                    dirty: false,
-                                };
-                                sess_state.items.push(item_record);
-                                sess_state.selected_item = Some(0);
-                                sess_state.selected_mark.push(Some(0));
-                            }
-                        }
-                    }
-                    Err(_) => continue,
-                }
+            let result = parse_textgrid(path)
+                .map(|(tg, tg_encoding)| scan_textgrid(proj_state, &compiled_rules, tg_folder, path, tg, tg_encoding));
+            if let Ok(Some(item)) = &result {
+                marks_found.fetch_add(item.found_mark_idxs.len(), Ordering::Relaxed);
+            }
+            let done = files_done.fetch_add(1, Ordering::Relaxed) + 1;
+            if done % report_every == 0 || done == files_total {
+                let _ = app.emit(
+                    "scan_progress",
+                    ScanProgress { files_done: done, files_total, marks_found: marks_found.load(Ordering::Relaxed) },
+                );
+            }
+            result
+        })
+        .collect();
+    if control.cancelled.load(Ordering::SeqCst) {
+        return Ok(ScanOutcome { cancelled: true, ..Default::default() });
+    }
+
+    let mut items = Vec::new();
+    let mut problems = Vec::new();
+    let mut scanned_files = HashSet::new();
+    for (path, result) in paths.iter().zip(results) {
+        match result {
+            Ok(Some(item)) => items.push(item),
+            Ok(None) => {}
+            Err(e) => {
+                problems.push(*e);
+                continue;
             }
-            let _ = app.emit("sync_session_state", sess_state.clone());
-            let _ = app.emit("list_item_done", ());
-            Ok(())
         }
-        Err(_) => Err("Failed to read the directory".into()),
+        scanned_files.insert(path.strip_prefix(tg_folder).unwrap_or(path).to_path_buf());
+    }
+    let session_state = app.state::<Mutex<SessionItems>>();
+    let mut sess_state = session_state.lock().map_err(|e| e.to_string())?;
+    // The project was loaded or cleared while the files were read.
+    if control.generation.load(Ordering::SeqCst) != generation {
+        return Ok(ScanOutcome { cancelled: true, ..Default::default() });
+    }
+    let mut decisions = sess_state.collect_decisions();
+    if !skip_broken_files && !problems.is_empty() {
+        let message = problems[0].to_string();
+        let filter = std::mem::take(&mut sess_state.filter);
+        *sess_state = SessionItems { problems, pending_decisions: decisions.into_values().collect(), filter, ..Default::default() };
+        let _ = app.emit("sync_session_state", sess_state.clone());
+        return Ok(ScanOutcome { error: Some(message), ..Default::default() });
+    }
+    let scanned_rules: HashSet<&str> = compiled_rules.iter().map(|c| c.rule.rule_name.as_str()).collect();
+    let mut lost = Vec::new();
+    for item in items.iter_mut() {
+        lost.extend(restore_decisions(item, &mut decisions));
     }
+    // Decisions on marks that no longer match are stale, also in files left without marks.
+    lost.extend(drop_stale_decisions(&mut decisions, &scanned_files, &scanned_rules));
+    lost.sort_by_key(|(d, _)| d.key());
+    *sess_state = SessionItems {
+        selected_item: None,
+        selected_mark: vec![Some(0); items.len()],
+        items,
+        problems,
+        pending_decisions: decisions.into_values().collect(),
+        filter: std::mem::take(&mut sess_state.filter),
+    };
+    sess_state.sort(proj_state.item_order, &proj_state.rules);
+    let _ = app.emit("sync_session_state", sess_state.clone());
+    Ok(ScanOutcome {
+        unrestored: lost.iter().map(|(d, reason)| describe_lost_decision(d, *reason)).collect(),
+        ..Default::default()
+    })
+}
+
+/// Tells the reviewer which saved decision a scan dropped and why.
+fn describe_lost_decision(decision: &MarkDecision, reason: LostReason) -> String {
+    let mark = format!("{}: {} at interval {}", decision.file.display(), decision.rule, decision.first_idx + 1);
+    let choice = match &decision.option {
+        Some(option) => format!("option {}", option),
+        None => "keeping it".to_string(),
+    };
+    match reason {
+        LostReason::OptionRemoved => format!("{}, the rule no longer has the {}", mark, choice),
+        LostReason::MarkChanged => format!("{}, the intervals changed since choosing {}", mark, choice),
+        LostReason::MarkGone => format!("{}, the rule no longer matches there, {} was chosen", mark, choice),
+    }
+}
+
+#[tauri::command]
+fn cancel_scan(scan_control: State<'_, ScanControl>) -> Result<(), String> {
+    scan_control.cancelled.store(true, Ordering::SeqCst);
+    Ok(())
 }
 
 #[tauri::command]
@@ -1108,62 +1665,46 @@ fn save_textgrids(
     let mut sess_state = state.lock().map_err(|e| e.to_string())?;
     let app_settings = app_settings.lock().map_err(|e| e.to_string())?;
     let mut resync = false;
+    let mut failures = Vec::new();
     for item in sess_state.items.iter_mut() {
         if item.dirty {
-            let opts = &item.replace_options;
-            let mut new_tg = item.tg_content.clone();
-            let phone_idx = if new_tg.items.len() > 1 { 1 } else { 0 };
-            for (i, mark_idx) in item.found_mark_idxs.iter().enumerate() {
-                if let Some(opt_idx) = item.selected_options[i] {
-                    let opt = opts[opt_idx as usize].split_whitespace().collect::<Vec<_>>();
-                    for j in 0..opt.len() {
-                        if opt[j] == "*" {
-                            continue;
-                        }
-                        new_tg
-                            .items
-                            .get_mut(phone_idx)
-                            .unwrap()
-                            .intervals
-                            .get_mut(*mark_idx + j)
-                            .unwrap()
-                            .text = opt[j].to_string();
-                    }
-                }
-            }
-            if app_settings.auto_backup {
-                let bak_path = item.tg_file.clone().with_extension("TextGrid.bak");
-                if !bak_path.exists() {
-                    fs::copy(&item.tg_file, &bak_path).map_err(|e| e.to_string())?;
-                }
-            }
-            let _ = fs::write(item.tg_file.clone(), textgrid_to_string(&new_tg))
-                .map_err(|e| e.to_string())
-                .and_then(|_| {
+            match write_item(item, app_settings.auto_backup, app_settings.force_utf8) {
+                Ok(()) => {
                     item.dirty = false;
                     item.original_options = item.selected_options.clone();
                     resync = true;
-                    Ok(())
-                });
+                }
+                Err(e @ ds_label_core::Error::Encode(_)) => {
+                    failures.push(format!("{}: {} Enable writing UTF-8 in the settings.", item.tg_file.display(), e))
+                }
+                Err(e) => failures.push(format!("{}: {}", item.tg_file.display(), e)),
+            }
         }
     }
     if resync {
         let _ = app.emit("sync_session_state", sess_state.clone());
     }
     let _ = app.emit("save_textgrids_done", ());
+    if !failures.is_empty() {
+        return Err(failures.join("\n"));
+    }
     Ok(())
 }
 
 #[tauri::command]
 fn init_state(
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
     session_state: State<'_, Mutex<SessionItems>>,
+    history: State<'_, Mutex<History>>,
+    scan_control: State<'_, ScanControl>,
 ) -> Result<(), String> {
     let mut app_state = state.lock().map_err(|e| e.to_string())?;
     let mut sess_state = session_state.lock().map_err(|e| e.to_string())?;
-    app_state.clone_from(&AppProjectState::default());
+    scan_control.invalidate();
+    app_state.clone_from(&ProjectState::default());
     sess_state.clone_from(&SessionItems::default());
+    history.lock().map_err(|e| e.to_string())?.clear(&app);
     let _ = app.emit("sync_app_state", app_state.clone());
     let _ = app.emit("sync_session_state", sess_state.clone());
     Ok(())
@@ -1172,28 +1713,30 @@ fn init_state(
 #[tauri::command]
 fn save_state(
     file_path: PathBuf,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
+    session_state: State<'_, Mutex<SessionItems>>,
 ) -> Result<(), String> {
     let app_state = state.lock().map_err(|e| e.to_string())?;
-    let app_state_json = serde_json::to_string(&*app_state).map_err(|e| e.to_string())?;
-    fs::write(file_path, format!("{{\"app_state\": {}}}", app_state_json)).map_err(|e| e.to_string())?;
-    Ok(())
+    let session_state = session_state.lock().map_err(|e| e.to_string())?;
+    write_project_file(&file_path, &app_state, &session_state.collect_decisions()).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
 fn load_state(
     file_path: PathBuf,
     app: tauri::AppHandle,
-    state: State<'_, Mutex<AppProjectState>>,
+    state: State<'_, Mutex<ProjectState>>,
     session_state: State<'_, Mutex<SessionItems>>,
+    history: State<'_, Mutex<History>>,
+    scan_control: State<'_, ScanControl>,
 ) -> Result<(), String> {
-    let file_contents = fs::read_to_string(file_path).map_err(|e| e.to_string())?;
-    let json: serde_json::Value = serde_json::from_str(&file_contents).map_err(|e| e.to_string())?;
-    let app_state_json = json["app_state"].to_string();
+    let (project, decisions) = read_project_file(&file_path).map_err(|e| e.to_string())?;
     let mut app_state = state.lock().map_err(|e| e.to_string())?;
     let mut session_state = session_state.lock().map_err(|e| e.to_string())?;
-    *app_state = serde_json::from_str(&app_state_json).map_err(|e| e.to_string())?;
-    *session_state = SessionItems::default();
+    scan_control.invalidate();
+    *app_state = project;
+    *session_state = SessionItems { pending_decisions: decisions, ..Default::default() };
+    history.lock().map_err(|e| e.to_string())?.clear(&app);
     let _ = app.emit("sync_app_state", app_state.clone());
     let _ = app.emit("sync_session_state", session_state.clone());
     Ok(())
@@ -1213,12 +1756,14 @@ fn play_selected(
             if let Some(wav_file) = &item.wav_file {
                 let tx = tx.lock().map_err(|e| e.to_string())?;
                 let tg = &item.tg_content;
-                let phone_begin = &tg.items[if tg.items.len() > 1 { 1 } else { 0 }].intervals[item.found_mark_idxs[mark_index as usize]];
-                let phone_end = &tg.items[tg.items.len() - 1].intervals[item.found_mark_idxs[mark_index as usize] + item.term_seq_length - 1];
+                let phones = &tg.items[item.found_mark_tiers[mark_index as usize]].intervals;
+                let mark_idxs = &item.found_mark_idxs[mark_index as usize];
+                let phone_begin = &phones[mark_idxs[0]];
+                let phone_end = &phones[*mark_idxs.last().unwrap()];
                 tx.send(SoundCommand::Play(
                     wav_file.clone(),
-                    (phone_begin.xmin * 1000. - 300.) as u64,
-                    (300. + 600. + (phone_end.xmax - phone_begin.xmin) * 1000.) as u64,
+                    (phone_begin.xmin.value() * 1000. - 300.) as u64,
+                    (300. + 600. + (phone_end.xmax.value() - phone_begin.xmin.value()) * 1000.) as u64,
                     app_settings.volume_factor,
                 ))
                 .map_err(|e| e.to_string())?;
@@ -1274,6 +1819,8 @@ fn update_settings(
     auto_next: bool,
     auto_play: bool,
     auto_scroll: bool,
+    force_utf8: bool,
+    skip_broken_files: bool,
     app: tauri::AppHandle,
     app_settings: State<'_, Mutex<AppSettings>>,
 ) -> Result<(), String> {
@@ -1284,6 +1831,8 @@ fn update_settings(
     app_settings.auto_next = auto_next;
     app_settings.auto_play = auto_play;
     app_settings.auto_scroll = auto_scroll;
+    app_settings.force_utf8 = force_utf8;
+    app_settings.skip_broken_files = skip_broken_files;
     let stores = app.state::<StoreCollection<Wry>>();
     let _ = with_store(
         app.clone(),
@@ -1453,8 +2002,10 @@ pub fn run() {
                 }
             }
         })
-        .manage(Mutex::new(AppProjectState::default()))
+        .manage(Mutex::new(ProjectState::default()))
         .manage(Mutex::new(SessionItems::default()))
+        .manage(ScanControl::default())
+        .manage(Mutex::new(History::default()))
         .invoke_handler(tauri::generate_handler![
             init_state,
             load_state,
@@ -1465,12 +2016,24 @@ pub fn run() {
             get_app_settings,
             get_default_paths,
             open_folder,
+            set_tier_names,
+            set_silence_marks,
+            set_scan_filter,
+            set_item_order,
+            set_phoneme_class,
+            remove_phoneme_class,
             list_items,
+            cancel_scan,
             play_selected,
             add_rule,
             rename_rule,
             remove_rule,
             select_rule,
+            toggle_active_rule,
+            set_rule_target_tier,
+            set_rule_contexts,
+            set_rule_word_condition,
+            set_rule_span,
             add_search_term,
             remove_search_term,
             rename_search_term,
@@ -1481,11 +2044,17 @@ pub fn run() {
             select_replace_option,
             prev_item,
             next_item,
+            next_undecided,
+            prev_undecided,
+            set_item_filter,
+            filtered_items,
             prev_mark,
             next_mark,
             select_item,
             select_mark,
             choose_a_replace_option,
+            undo,
+            redo,
             list_audio_output_devices,
             select_audio_output_device,
             test_output_device,